@@ -0,0 +1,54 @@
+#!/bin/bash
+
+# Times each given same-file executable comparing two identical files, and two
+# files differing only in their last byte, of the given size.
+#
+# Usage: bench.sh SIZE_MIB EXECUTABLE...
+#
+# The files are created in ${TMPDIR:-/tmp}; pass several executables (e.g. one
+# built from an older revision) to compare them.  Each run is preceded by a run
+# to warm the page cache, so this measures CPU and memory throughput rather
+# than disk throughput.  Each executable is run with each thread count in
+# ${THREADS} (by default, 1 and the number of cores) as --threads-max.
+
+set -e
+
+SELF_NAME="$(basename "${0}")"
+
+if [[ ${#} -lt 2 ]]; then
+    echo "Usage: ${SELF_NAME} SIZE_MIB EXECUTABLE..." >&2
+    exit 2
+fi
+
+size_mib="${1}"
+shift
+
+dir="$(mktemp -d "${TMPDIR:-/tmp}/${SELF_NAME}.XXXXXXXXXX")"
+trap 'rm -rf "${dir}"' EXIT
+
+head -c "$((size_mib * 1024 * 1024 - 1))" /dev/urandom > "${dir}/different"
+cp "${dir}/different" "${dir}/left"
+printf '\x00' >> "${dir}/different"
+printf '\x01' >> "${dir}/left"
+cp "${dir}/left" "${dir}/same"
+
+# hashes cached by one run must not spare the next one from comparing
+export XDG_CACHE_HOME="${dir}/cache"
+
+for executable in "${@}"; do
+    for threads in ${THREADS:-1 $(nproc)}; do
+        for right in same different; do
+            run=("${executable}" --quiet --threads-max "${threads}"
+                 "${dir}/left" "${dir}/${right}")
+            "${run[@]}" || true
+            rm -rf "${XDG_CACHE_HOME}"
+            start="$(date +%s.%N)"
+            "${run[@]}" || true
+            end="$(date +%s.%N)"
+            awk -v executable="${executable}" -v threads="${threads}" \
+                -v right="${right}" -v start="${start}" -v end="${end}" \
+                'BEGIN { printf "%s\t-t %s\t%s\t%.3f s\n",
+                         executable, threads, right, end - start }'
+        done
+    done
+done
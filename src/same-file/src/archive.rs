@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::Value;
+use std::io::prelude::*;
+use std::{fmt, fs, io, path};
+use blake3;
+use tar;
+use zip;
+use metadata::Attribute;
+use output::{path_to_json, ToJson};
+use {keep_going, stream, Error, ExitStatus, Options, Res};
+
+/// The magic bytes a zip file starts with, either with its first member or,
+/// if it has none, with the end of its central directory.
+const MAGIC_ZIP: &[u8] = b"PK\x03\x04";
+const MAGIC_ZIP_EMPTY: &[u8] = b"PK\x05\x06";
+/// The mask of the bits of a mode which are not the file type.
+const MODE_BITS: u32 = 0o7777;
+
+/// Compare the members of the first of `archives` to those of each of the
+/// others, matched by their paths.
+pub fn compare_archives(archives: &[&path::Path], options: &Options,
+                        errors: &mut Vec<Error>) -> Res<ArchiveComparison>
+{
+    let (first, rest) = archives.split_at(1);
+    let first = *first.first().expect("no first element in first");
+    let mut differences = Vec::new();
+    let members_first =
+        match keep_going(list(first, options), options, errors)? {
+            Some(members) => members,
+            None => return Ok(ArchiveComparison { differences }),
+        };
+
+    for &archive in rest {
+        let members = match keep_going(list(archive, options), options,
+                                       errors)?
+        {
+            Some(members) => members,
+            None => continue,
+        };
+        let paths = members_first.keys().chain(members.keys())
+            .collect::<BTreeSet<_>>();
+        for path in paths {
+            let difference = match (members_first.get(path),
+                                    members.get(path)) {
+                (Some(_), None) => Difference::Removed {
+                    archive: archive.into(), path: path.clone(),
+                },
+                (None, Some(_)) => Difference::Added {
+                    archive: archive.into(), path: path.clone(),
+                },
+                (Some(left), Some(right)) => {
+                    let changes = left.compare(right, &options.attributes);
+                    if changes.is_empty() {
+                        continue;
+                    }
+                    Difference::Changed {
+                        archive: archive.into(), path: path.clone(), changes,
+                    }
+                },
+                (None, None) => unreachable!("path in neither archive"),
+            };
+            differences.push(difference);
+        }
+    }
+    Ok(ArchiveComparison { differences })
+}
+
+/// A member of an archive, with a hash of its contents rather than the
+/// contents themselves, so that no member needs to be kept or extracted.
+struct Member {
+    kind: Kind,
+    len: u64,
+    hash: Option<blake3::Hash>,
+    /// The path a symbolic or hard link points to.
+    target: Option<path::PathBuf>,
+    /// The permissions and owner, if the archive records them; directories
+    /// implied by the paths of other members have neither.
+    mode: Option<u32>,
+    owner: Option<(u64, u64)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+    Other,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::File => "regular file",
+            Kind::Directory => "directory",
+            Kind::Symlink => "symbolic link",
+            Kind::HardLink => "hard link",
+            Kind::Other => "other",
+        }
+    }
+}
+
+impl Member {
+    /// A directory only implied by the paths of other members.
+    fn implied() -> Self {
+        Member {
+            kind: Kind::Directory, len: 0, hash: None, target: None,
+            mode: None, owner: None,
+        }
+    }
+
+    /// Find how `other` differs from this member, in its kind, its contents,
+    /// its target, and in those of `attributes` the archives both record.
+    fn compare(&self, other: &Member, attributes: &[Attribute]) ->
+        Vec<Change>
+    {
+        let mut changes = Vec::new();
+        if self.kind != other.kind {
+            changes.push(Change::new("type", self.kind.name(),
+                                     other.kind.name()));
+            return changes;
+        }
+        if self.len != other.len {
+            changes.push(Change::new("size", format!("{} B", self.len),
+                                     format!("{} B", other.len)));
+        } else if self.hash != other.hash {
+            changes.push(Change { what: "contents", values: None });
+        }
+        if self.target != other.target {
+            let target = |member: &Member| {
+                member.target.as_ref()
+                    .map_or("(none)".to_string(), |target| {
+                        format!("{:?}", target)
+                    })
+            };
+            changes.push(Change::new("target", target(self), target(other)));
+        }
+        for &attribute in attributes {
+            match (attribute, self.mode, other.mode, self.owner, other.owner) {
+                (Attribute::Mode, Some(left), Some(right), _, _)
+                    if left != right =>
+                    changes.push(Change::new("mode",
+                                             format!("{:04o}", left),
+                                             format!("{:04o}", right))),
+                (Attribute::Owner, _, _, Some(left), Some(right))
+                    if left != right =>
+                    changes.push(Change::new("owner",
+                                             format!("{}:{}", left.0, left.1),
+                                             format!("{}:{}", right.0,
+                                                     right.1))),
+                _ => (),
+            }
+        }
+        changes
+    }
+}
+
+/// List the members of the archive at `path`, a zip file or a tar file, which
+/// may be compressed, by their paths.
+fn list(path: &path::Path, options: &Options) ->
+    Res<BTreeMap<path::PathBuf, Member>>
+{
+    let mut members = if !stream::is_stdin(path) && is_zip(path)? {
+        list_zip(path, options)?
+    } else {
+        list_tar(path, options)?
+    };
+
+    // directories are not always archived along with their members
+    let parents = members.keys()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map(path::PathBuf::from)
+        .collect::<Vec<_>>();
+    for parent in parents {
+        members.entry(parent).or_insert_with(Member::implied);
+    }
+    Ok(members)
+}
+
+fn is_zip(path: &path::Path) -> Res<bool> {
+    let file = fs::File::open(path).map_err(|e| (e, path))?;
+    let mut magic = Vec::with_capacity(MAGIC_ZIP.len());
+    file.take(MAGIC_ZIP.len() as u64).read_to_end(&mut magic)
+        .map_err(|e| (e, path))?;
+    Ok(magic == MAGIC_ZIP || magic == MAGIC_ZIP_EMPTY)
+}
+
+fn list_zip(path: &path::Path, options: &Options) ->
+    Res<BTreeMap<path::PathBuf, Member>>
+{
+    if options.verbose {
+        eprintln!("{:?}: reading as a zip file", path);
+    }
+    let invalid = |e: zip::result::ZipError| {
+        Error::ArchiveInvalid(path.into(), e.into())
+    };
+    let file = fs::File::open(path).map_err(|e| (e, path))?;
+    let mut archive = zip::ZipArchive::new(io::BufReader::new(file))
+        .map_err(invalid)?;
+    let mut members = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(invalid)?;
+        let kind = if file.is_dir() {
+            Kind::Directory
+        } else if file.is_symlink() {
+            Kind::Symlink
+        } else {
+            Kind::File
+        };
+        let mode = file.unix_mode().map(|mode| mode & MODE_BITS);
+        let member_path = normalize(path::Path::new(file.name()));
+        let mut member = Member {
+            kind, len: 0, hash: None, target: None, mode, owner: None,
+        };
+        match kind {
+            // the contents of a symbolic link are its target
+            Kind::Symlink => {
+                let mut target = Vec::new();
+                file.read_to_end(&mut target)
+                    .map_err(|e| Error::ArchiveInvalid(path.into(), e))?;
+                member.target =
+                    Some(String::from_utf8_lossy(&target).as_ref().into());
+            },
+            Kind::File => {
+                let mut contents = Hashed::default();
+                io::copy(&mut file, &mut contents)
+                    .map_err(|e| Error::ArchiveInvalid(path.into(), e))?;
+                member.len = contents.len;
+                member.hash = Some(contents.hasher.finalize());
+            },
+            _ => (),
+        }
+        members.insert(member_path, member);
+    }
+    Ok(members)
+}
+
+fn list_tar(path: &path::Path, options: &Options) ->
+    Res<BTreeMap<path::PathBuf, Member>>
+{
+    let reader: Box<dyn Read> = if stream::is_stdin(path) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(path).map_err(|e| (e, path))?)
+    };
+    let reader = stream::decompress(path, reader, options)?;
+    if options.verbose {
+        eprintln!("{:?}: reading as a tar file", path);
+    }
+    let invalid = |e| Error::ArchiveInvalid(path.into(), e);
+    let mut archive = tar::Archive::new(reader);
+    let mut members = BTreeMap::new();
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous =>
+                Kind::File,
+            tar::EntryType::Directory => Kind::Directory,
+            tar::EntryType::Symlink => Kind::Symlink,
+            tar::EntryType::Link => Kind::HardLink,
+            tar::EntryType::XGlobalHeader => continue,
+            _ => Kind::Other,
+        };
+        let mode = header.mode().map_err(invalid)? & MODE_BITS;
+        let owner = (header.uid().map_err(invalid)?,
+                     header.gid().map_err(invalid)?);
+        let target = match kind {
+            Kind::Symlink | Kind::HardLink => entry.link_name()
+                .map_err(invalid)?
+                .map(|target| target.into_owned()),
+            _ => None,
+        };
+        let member_path = normalize(&entry.path().map_err(invalid)?);
+        let mut contents = Hashed::default();
+        if kind == Kind::File {
+            io::copy(&mut entry, &mut contents).map_err(invalid)?;
+        }
+        members.insert(member_path, Member {
+            kind,
+            len: contents.len,
+            hash: if kind == Kind::File {
+                Some(contents.hasher.finalize())
+            } else {
+                None
+            },
+            target,
+            mode: Some(mode),
+            owner: Some(owner),
+        });
+    }
+    Ok(members)
+}
+
+/// The path of a member without a leading `./` or `/`, or a trailing `/`.
+fn normalize(path: &path::Path) -> path::PathBuf {
+    path.components()
+        .filter(|component| {
+            matches!(component,
+                     path::Component::Normal(..) | path::Component::ParentDir)
+        })
+        .collect()
+}
+
+/// The length and hash of the contents written to it.
+#[derive(Default)]
+struct Hashed {
+    len: u64,
+    hasher: blake3::Hasher,
+}
+
+impl Write for Hashed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.len += buf.len() as u64;
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A way in which a member differs, naming what differs, with the value of
+/// it in each archive, if it is worth printing.
+struct Change {
+    what: &'static str,
+    values: Option<(String, String)>,
+}
+
+impl Change {
+    fn new<L: Into<String>, R: Into<String>>(what: &'static str, left: L,
+                                             right: R) -> Self
+    {
+        Change { what, values: Some((left.into(), right.into())) }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.values {
+            Some((ref left, ref right)) =>
+                write!(f, "{}: {} -- {}", self.what, left, right),
+            None => write!(f, "{}", self.what),
+        }
+    }
+}
+
+pub struct ArchiveComparison {
+    differences: Vec<Difference>,
+}
+
+/// A member in only the first archive or only another one, or which differs
+/// between them.
+enum Difference {
+    Added { archive: path::PathBuf, path: path::PathBuf },
+    Removed { archive: path::PathBuf, path: path::PathBuf },
+    Changed { archive: path::PathBuf, path: path::PathBuf,
+              changes: Vec<Change> },
+}
+
+impl fmt::Display for ArchiveComparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.differences.is_empty() {
+            return write!(f, "archives are the same");
+        }
+        for (i, difference) in self.differences.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Added { archive, path } =>
+                write!(f, "added in {:?}: {:?}", archive, path),
+            Difference::Removed { archive, path } =>
+                write!(f, "removed in {:?}: {:?}", archive, path),
+            Difference::Changed { archive, path, changes } => {
+                write!(f, "changed in {:?}: {:?}: ", archive, path)?;
+                for (i, change) in changes.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", change)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl ToJson for ArchiveComparison {
+    fn to_json(&self) -> Vec<Value> {
+        if self.differences.is_empty() {
+            return vec![json!({ "kind": "same" })];
+        }
+        self.differences.iter()
+            .map(|difference| match difference {
+                Difference::Added { archive, path } => json!({
+                    "kind": "member_added",
+                    "archive": path_to_json(archive),
+                    "path": path_to_json(path),
+                }),
+                Difference::Removed { archive, path } => json!({
+                    "kind": "member_removed",
+                    "archive": path_to_json(archive),
+                    "path": path_to_json(path),
+                }),
+                Difference::Changed { archive, path, changes } => json!({
+                    "kind": "member_changed",
+                    "archive": path_to_json(archive),
+                    "path": path_to_json(path),
+                    "changes": changes.iter()
+                        .map(|change| json!({
+                            "what": change.what,
+                            "values": change.values.as_ref()
+                                .map(|(left, right)| [left, right]),
+                        }))
+                        .collect::<Vec<_>>(),
+                }),
+            })
+            .collect()
+    }
+}
+
+impl From<ArchiveComparison> for ExitStatus {
+    fn from(comparison: ArchiveComparison) -> Self {
+        if comparison.differences.is_empty() {
+            ExitStatus::Same
+        } else {
+            ExitStatus::Different
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::os::unix::fs::MetadataExt;
+use std::{env, fs, io, path, process, sync, time};
+use hash::Algorithm;
+use {stream, Options, Res};
+
+/// The first line of a cache file, changed whenever the format or the hashes
+/// change, which discards older caches.
+const HEADER: &str = "same-file cache 1";
+/// Files modified this recently may still be modified within the same
+/// timestamp, so their hashes are not cached.
+const MODIFIED_RECENTLY: time::Duration = time::Duration::from_secs(2);
+
+/// Hashes of file contents, stored across runs and keyed by the device and
+/// inode numbers of each file; an entry is only valid as long as the size and
+/// modification time of the file are still the same.
+pub struct Cache {
+    path: path::PathBuf,
+    /// The entries, loaded when first used, so that modes which hash nothing
+    /// never read the cache.
+    entries: sync::OnceLock<sync::Mutex<HashMap<(u64, u64), Entry>>>,
+    changed: sync::atomic::AtomicBool,
+}
+
+#[derive(Clone, Default)]
+struct Entry {
+    len: u64,
+    mtime_ns: i64,
+    /// The number of bytes hashed at the start and at the end of the file,
+    /// and their hash.
+    partial: Option<(u64, String)>,
+    full: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    /// The BLAKE3 hash of the first and last given number of bytes.
+    Partial(u64),
+    /// The BLAKE3 hash of the whole contents.
+    Full,
+}
+
+impl Cache {
+    /// The default location of the cache, under the XDG cache directory.
+    pub fn path_default() -> Option<path::PathBuf> {
+        let dir = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| path::Path::new(dir).is_absolute())
+            .map(path::PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME")
+                    .map(|home| path::Path::new(&home).join(".cache"))
+            })?;
+        Some(dir.join("same-file").join("hashes"))
+    }
+
+    /// The cache at `path`, which is only read once used.
+    pub fn open(path: &path::Path) -> Self {
+        Cache {
+            path: path.into(),
+            entries: sync::OnceLock::new(),
+            changed: sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// The entries of the cache, read first if they were not yet; a cache
+    /// which cannot be read is empty, as a missing or outdated one is.
+    fn entries(&self) -> sync::MutexGuard<'_, HashMap<(u64, u64), Entry>> {
+        self.entries
+            .get_or_init(|| {
+                sync::Mutex::new(read(&self.path).unwrap_or_else(|e| {
+                    eprintln!("cannot read the cache {:?}: {}", self.path, e);
+                    HashMap::new()
+                }))
+            })
+            .lock()
+            .unwrap()
+    }
+
+    /// Write the cache back, if anything changed, replacing it atomically.
+    pub fn save(&self) -> Res<()> {
+        if !self.changed.load(sync::atomic::Ordering::Relaxed) {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| (e, dir))?;
+        }
+        let name = self.path.file_name().expect("no file name in path");
+        let tmp = self.path.with_file_name(format!(".{}.{}",
+                                                   name.to_string_lossy(),
+                                                   process::id()));
+        let result = self.write(&tmp)
+            .and_then(|()| fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err((e, &self.path).into());
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &path::Path) -> io::Result<()> {
+        let file = fs::File::create(path)?;
+        let mut file = io::BufWriter::new(file);
+        writeln!(file, "{}", HEADER)?;
+        let entries = self.entries();
+        for (&(dev, ino), entry) in entries.iter() {
+            let (partial_len, partial) = match entry.partial {
+                Some((len, ref hash)) => (len, hash.as_str()),
+                None => (0, "-"),
+            };
+            writeln!(file, "{} {} {} {} {} {} {}",
+                     dev, ino, entry.len, entry.mtime_ns, partial_len,
+                     partial, entry.full.as_deref().unwrap_or("-"))?;
+        }
+        file.into_inner()?.sync_all()
+    }
+
+    fn get(&self, metadata: &fs::Metadata, kind: Kind) -> Option<String> {
+        let mut entries = self.entries();
+        let id = (metadata.dev(), metadata.ino());
+        let entry = entries.get(&id)?;
+        if (entry.len, entry.mtime_ns) != (metadata.len(), mtime_ns(metadata))
+        {
+            // the file changed, or the inode was reused for another one
+            entries.remove(&id);
+            self.changed.store(true, sync::atomic::Ordering::Relaxed);
+            return None;
+        }
+        match kind {
+            Kind::Partial(len) => match entry.partial {
+                Some((len_hashed, ref hash)) if len_hashed == len =>
+                    Some(hash.clone()),
+                _ => None,
+            },
+            Kind::Full => entry.full.clone(),
+        }
+    }
+
+    fn set(&self, metadata: &fs::Metadata, kind: Kind, hash: &str) {
+        let modified = metadata.modified().ok()
+            .and_then(|modified| modified.elapsed().ok());
+        match modified {
+            Some(elapsed) if elapsed >= MODIFIED_RECENTLY => (),
+            _ => return,
+        }
+        let mut entries = self.entries();
+        let entry = entries.entry((metadata.dev(), metadata.ino()))
+            .or_default();
+        if (entry.len, entry.mtime_ns) != (metadata.len(), mtime_ns(metadata))
+        {
+            *entry = Entry {
+                len: metadata.len(),
+                mtime_ns: mtime_ns(metadata),
+                ..Entry::default()
+            };
+        }
+        match kind {
+            Kind::Partial(len) => entry.partial = Some((len, hash.into())),
+            Kind::Full => entry.full = Some(hash.into()),
+        }
+        self.changed.store(true, sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Read the entries of the cache at `path`; invalid lines are left out.
+fn read(path: &path::Path) -> io::Result<HashMap<(u64, u64), Entry>> {
+    let mut entries = HashMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            return Ok(entries),
+        Err(e) => return Err(e),
+    };
+    let mut lines = io::BufReader::new(file).lines();
+    if lines.next().transpose()?.as_deref() == Some(HEADER) {
+        for line in lines {
+            if let Some((id, entry)) = parse_line(&line?) {
+                entries.insert(id, entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Parse a line as written by `Cache::write`, where a hash is a BLAKE3 hash
+/// in lowercase hexadecimal, or `-` if there is none.
+fn parse_line(line: &str) -> Option<((u64, u64), Entry)> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    if fields.len() != 7 {
+        return None;
+    }
+    let hash = |field: &str| match field {
+        "-" => Some(None),
+        hash if hash.len() == Algorithm::Blake3.hex_len() &&
+            hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+            Some(Some(hash.to_string())),
+        _ => None,
+    };
+    let id = (fields[0].parse().ok()?, fields[1].parse().ok()?);
+    let entry = Entry {
+        len: fields[2].parse().ok()?,
+        mtime_ns: fields[3].parse().ok()?,
+        partial: match hash(fields[5])? {
+            Some(hash) => Some((fields[4].parse().ok()?, hash)),
+            None => None,
+        },
+        full: hash(fields[6])?,
+    };
+    Some((id, entry))
+}
+
+fn mtime_ns(metadata: &fs::Metadata) -> i64 {
+    metadata.mtime()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(metadata.mtime_nsec())
+}
+
+/// Look up the hash of `kind` of the file at `path` in the cache of
+/// `options`, if any, or compute it with `hash` and cache it.
+pub fn hash<F>(path: &path::Path, kind: Kind, options: &Options, hash: F) ->
+    Res<String>
+    where F: FnOnce() -> Res<String>
+{
+    let cache = match options.cache {
+        Some(ref cache) if !stream::is_stdin(path) => cache,
+        _ => return hash(),
+    };
+    let metadata = fs::metadata(path).map_err(|e| (e, path))?;
+    if !metadata.is_file() {
+        return hash();
+    }
+    if let Some(hash) = cache.get(&metadata, kind) {
+        if options.verbose {
+            eprintln!("{:?}: hash read from the cache", path);
+        }
+        return Ok(hash);
+    }
+    let hash = hash()?;
+    cache.set(&metadata, kind, &hash);
+    Ok(hash)
+}
+
+/// Compute the hash of `kind` of the file at `path` with `hash`, and cache it
+/// in the cache of `options`, if any, without looking it up first: a cached
+/// hash may prove files different, but never that a file has the contents
+/// expected.
+pub fn rehash<F>(path: &path::Path, kind: Kind, options: &Options, hash: F) ->
+    Res<String>
+    where F: FnOnce() -> Res<String>
+{
+    let cache = match options.cache {
+        Some(ref cache) if !stream::is_stdin(path) => cache,
+        _ => return hash(),
+    };
+    let metadata = fs::metadata(path).map_err(|e| (e, path))?;
+    let hash = hash()?;
+    if metadata.is_file() {
+        cache.set(&metadata, kind, &hash);
+    }
+    Ok(hash)
+}
+
+/// Whether the cache of `options` proves the contents of the files at `left`
+/// and `right` different, by their full hashes, without reading them.
+pub fn differ(left: &path::Path, right: &path::Path, options: &Options) ->
+    bool
+{
+    let cache = match options.cache {
+        Some(ref cache) => cache,
+        None => return false,
+    };
+    let full = |path: &path::Path| {
+        let metadata = fs::metadata(path).ok()
+            .filter(|metadata| metadata.is_file())?;
+        cache.get(&metadata, Kind::Full)
+    };
+    match (full(left), full(right)) {
+        (Some(hash_left), Some(hash_right)) => hash_left != hash_right,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str =
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+
+    #[test]
+    fn parse_line_valid() {
+        let (id, entry) = parse_line(&format!("1 2 3 -4 5 {} {}", HASH, HASH))
+            .unwrap();
+        assert_eq!(id, (1, 2));
+        assert_eq!((entry.len, entry.mtime_ns), (3, -4));
+        assert_eq!(entry.partial, Some((5, HASH.to_string())));
+        assert_eq!(entry.full.as_deref(), Some(HASH));
+
+        let (_, entry) = parse_line("1 2 3 4 0 - -").unwrap();
+        assert_eq!((entry.partial, entry.full), (None, None));
+    }
+
+    #[test]
+    fn parse_line_malformed() {
+        for line in &["", "1 2 3 4 0 -", "1 2 3 4 0 - - -", "1  2 3 4 0 - -",
+                      "1 2 3 4 0 - - ", "a 2 3 4 0 - -", "1 2 -3 4 0 - -",
+                      "1 2 3 4.5 0 - -", &format!("1 2 3 4 x {} -", HASH),
+                      &format!("1 2 3 4 0 - {}", &HASH[1..]),
+                      &format!("1 2 3 4 0 - {}0", HASH),
+                      &format!("1 2 3 4 0 - {}", HASH.to_uppercase()),
+                      &format!("1 2 3 4 0 - {}", HASH.replace('a', "g"))]
+        {
+            assert!(parse_line(line).is_none(), "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,820 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic;
+use std::{cmp, fs, io, path, sync, thread};
+use libc;
+use memmap2;
+use {fiemap, Comparison, Extent, Options, Res};
+
+/// The minimum number of blocks to compare per thread.
+const THREAD_BLOCKS_MIN: u64 = 500;
+/// The number of blocks in a chunk, the unit of work handed to each thread.
+const CHUNK_BLOCKS: u64 = 64;
+
+/// Compares a file to others, or with --skip-left, --skip-right or --bytes
+/// ranges of them, in which case offsets are counted from their starts.
+pub struct Comparer {
+    to_path: path::PathBuf,
+    to: Source,
+    /// The length of the file, or of the range of it, to compare.
+    to_len: u64,
+    threads: usize,
+    options: Options,
+}
+
+impl Comparer {
+    pub fn new(to: &path::Path, to_len: u64, options: &Options) -> Res<Self> {
+        let blocks = to_len / options.buffer_size as u64;
+        let threads = cmp::min(blocks / THREAD_BLOCKS_MIN,
+                               options.threads_max as u64);
+        let threads = cmp::max(threads as usize, 1);
+        Ok(Self {
+            to_path: to.into(),
+            to: Source::open(to, None, options)?,
+            to_len,
+            threads,
+            options: options.clone(),
+        })
+    }
+
+    pub fn compare(&mut self, file_path: &path::Path) -> Res<Comparison> {
+        let file = Source::open(file_path, Some(self.to.id.0), &self.options)?;
+        if let Some(comparison) = self.same_file(&file, file_path) {
+            return Ok(comparison);
+        }
+        // a difference found by sampling need not be the first one, so only
+        // the contents before it are still compared
+        let mut sampled = None;
+        if let Some(samples) = self.options.samples {
+            match self.sample_file(&file, file_path, samples)? {
+                (Comparison::Same, false) => (),
+                (Comparison::DifferentSample {
+                    left, right, offset, byte_left, byte_right,
+                }, false) => sampled = Some(Comparison::DifferentContents {
+                    left, right, offset, byte_left, byte_right,
+                    line_column: None,
+                }),
+                (comparison, _) => return Ok(comparison),
+            }
+        }
+        let shared = self.shared_ranges(&file, file_path);
+
+        // the earliest difference found so far, and its offset; chunks
+        // starting at or after it need not be checked
+        let stop = atomic::AtomicU64::new(match sampled {
+            Some(Comparison::DifferentContents { offset, .. }) => offset,
+            _ => u64::MAX,
+        });
+        let earliest =
+            sync::Mutex::new(sampled.unwrap_or(Comparison::Same));
+
+        self.scan_chunks(&stop, |beg, end, buf_to, buf_file| {
+            let mut pos = beg;
+            while pos < end && pos < stop.load(atomic::Ordering::Relaxed) {
+                let (step_end, skip) = self.step(&file, &shared, pos, end);
+                if skip {
+                    pos = step_end;
+                    continue;
+                }
+                let len = (step_end - pos) as usize;
+                let bytes = self.read_at(&file, file_path, pos,
+                                         &mut buf_to[..len],
+                                         &mut buf_file[..len])?;
+                let comparison = match bytes {
+                    Some((bytes_to, bytes_file)) => {
+                        if bytes_to == bytes_file {
+                            pos += len as u64;
+                            continue;
+                        }
+                        let i = bytes_to.iter().zip(bytes_file)
+                            .position(|(byte_to, byte_file)| {
+                                byte_to != byte_file
+                            })
+                            .expect("no difference in different buffers");
+                        Comparison::DifferentContents {
+                            left: self.to_path.clone(),
+                            right: file_path.into(),
+                            offset: pos + i as u64,
+                            byte_left: bytes_to[i],
+                            byte_right: bytes_file[i],
+                            line_column: None,
+                        }
+                    },
+                    None => self.different_size(file_path)?,
+                };
+
+                let offset = match comparison {
+                    Comparison::DifferentContents { offset, .. } => offset,
+                    _ => pos,
+                };
+                let mut earliest = earliest.lock().unwrap();
+                if offset < stop.load(atomic::Ordering::Relaxed) {
+                    stop.store(offset, atomic::Ordering::Relaxed);
+                    *earliest = comparison;
+                }
+                break;
+            }
+            Ok(())
+        })?;
+
+        Ok(earliest.into_inner().unwrap())
+    }
+
+    /// Compare only some blocks of the file at `file_path`, as before comparing
+    /// it fully; return whether that compared all of it, with the comparison.
+    pub fn sample(&mut self, file_path: &path::Path) ->
+        Res<(Comparison, bool)>
+    {
+        let file = Source::open(file_path, Some(self.to.id.0), &self.options)?;
+        if let Some(comparison) = self.same_file(&file, file_path) {
+            return Ok((comparison, true));
+        }
+        let samples = self.options.samples.unwrap_or(0);
+        self.sample_file(&file, file_path, samples)
+    }
+
+    /// Compare the first and last blocks of `file`, and `samples` blocks in
+    /// between, each at a random offset within one of as many stretches of
+    /// the same size; a small file is compared fully, block by block.  Return
+    /// whether all blocks were compared, with the comparison.
+    fn sample_file(&self, file: &Source, file_path: &path::Path,
+                   samples: usize) -> Res<(Comparison, bool)>
+    {
+        let block_len = self.options.buffer_size as u64;
+        let blocks = self.to_len.div_ceil(block_len);
+        let samples = samples as u64;
+        let all = blocks <= samples + 2;
+        let offsets = if all {
+            (0..blocks).map(|block| block * block_len).collect()
+        } else {
+            let random = RandomState::new();
+            let inner = blocks - 2;
+            let mut offsets = vec![0, (blocks - 1) * block_len];
+            for i in 0..samples {
+                let beg = 1 + inner * i / samples;
+                let end = 1 + inner * (i + 1) / samples;
+                let mut hasher = random.build_hasher();
+                hasher.write_u64(i);
+                let block = beg + hasher.finish() % (end - beg);
+                offsets.push(block * block_len);
+            }
+            offsets
+        };
+        if self.options.verbose {
+            eprintln!("{:?}: sampling {} blocks", file_path, offsets.len());
+        }
+
+        let mut buf_to = vec![0; self.options.buffer_size];
+        let mut buf_file = vec![0; self.options.buffer_size];
+        for offset in offsets {
+            let end = cmp::min(offset + block_len, self.to_len);
+            let difference = self.sample_block(file, file_path, offset, end,
+                                               &mut buf_to, &mut buf_file)?;
+            let (offset, byte_left, byte_right) = match difference {
+                Sampled::Same => continue,
+                Sampled::Different(offset, byte_left, byte_right) =>
+                    (offset, byte_left, byte_right),
+                Sampled::Ended =>
+                    return Ok((self.different_size(file_path)?, all)),
+            };
+            let (left, right) = (self.to_path.clone(), file_path.into());
+            // blocks are compared in order when all of them are, so the
+            // difference is then the first one
+            let comparison = if all {
+                Comparison::DifferentContents {
+                    left, right, offset, byte_left, byte_right,
+                    line_column: None,
+                }
+            } else {
+                Comparison::DifferentSample {
+                    left, right, offset, byte_left, byte_right,
+                }
+            };
+            return Ok((comparison, all));
+        }
+        Ok((Comparison::Same, all))
+    }
+
+    /// Compare the block of `file` from `beg` to `end`, in steps which do not
+    /// cross the start or end of a hole in either file.
+    fn sample_block(&self, file: &Source, file_path: &path::Path, beg: u64,
+                    end: u64, buf_to: &mut [u8], buf_file: &mut [u8]) ->
+        Res<Sampled>
+    {
+        let mut pos = beg;
+        while pos < end {
+            let (step_end, skip) = self.step(file, &[], pos, end);
+            if skip {
+                pos = step_end;
+                continue;
+            }
+            let len = (step_end - pos) as usize;
+            let bytes = self.read_at(file, file_path, pos, &mut buf_to[..len],
+                                     &mut buf_file[..len])?;
+            let (bytes_to, bytes_file) = match bytes {
+                Some(bytes) => bytes,
+                None => return Ok(Sampled::Ended),
+            };
+            if bytes_to != bytes_file {
+                let i = bytes_to.iter().zip(bytes_file)
+                    .position(|(byte_to, byte_file)| byte_to != byte_file)
+                    .expect("no difference in different buffers");
+                return Ok(Sampled::Different(pos + i as u64, bytes_to[i],
+                                             bytes_file[i]));
+            }
+            pos = step_end;
+        }
+        Ok(Sampled::Same)
+    }
+
+    pub fn list_differences(&mut self, file_path: &path::Path,
+                            differences_max: usize) -> Res<Comparison>
+    {
+        let file = Source::open(file_path, Some(self.to.id.0), &self.options)?;
+        if let Some(comparison) = self.same_file(&file, file_path) {
+            return Ok(comparison);
+        }
+        let shared = self.shared_ranges(&file, file_path);
+
+        // an extent at the start of a chunk may be merged with the last one of
+        // the previous chunk, so each chunk lists one extra extent
+        let limit = differences_max.saturating_add(1);
+        let stop = atomic::AtomicU64::new(u64::MAX);
+        let listing = sync::Mutex::new(Listing::default());
+
+        self.scan_chunks(&stop, |beg, end, buf_to, buf_file| {
+            let mut extents: Vec<Extent> = Vec::new();
+            let mut pos = beg;
+            'buffers: while pos < end {
+                let (step_end, skip) = self.step(&file, &shared, pos, end);
+                if skip {
+                    pos = step_end;
+                    continue;
+                }
+                let len = (step_end - pos) as usize;
+                let bytes = self.read_at(&file, file_path, pos,
+                                         &mut buf_to[..len],
+                                         &mut buf_file[..len])?;
+                let (bytes_to, bytes_file) = match bytes {
+                    Some(bytes) => bytes,
+                    None => {
+                        let comparison = self.different_size(file_path)?;
+                        let mut listing = listing.lock().unwrap();
+                        listing.comparison = Some(comparison);
+                        stop.store(0, atomic::Ordering::Relaxed);
+                        return Ok(());
+                    },
+                };
+                if bytes_to != bytes_file {
+                    let bytes = bytes_to.iter().zip(bytes_file);
+                    for (i, (byte_to, byte_file)) in bytes.enumerate() {
+                        if byte_to == byte_file {
+                            continue;
+                        }
+                        let offset = pos + i as u64;
+                        if let Some(last) = extents.last_mut() {
+                            if last.offset + last.len == offset {
+                                last.len += 1;
+                                continue;
+                            }
+                        }
+                        if extents.len() == limit {
+                            // the last extent is complete, since a new one
+                            // starts after it
+                            break 'buffers;
+                        }
+                        extents.push(Extent { offset, len: 1 });
+                    }
+                }
+                pos += len as u64;
+            }
+
+            let mut listing = listing.lock().unwrap();
+            listing.chunks.insert(beg, (end, extents));
+            listing.merge_done();
+            if listing.extents.len() > differences_max {
+                stop.store(0, atomic::Ordering::Relaxed);
+            }
+            Ok(())
+        })?;
+
+        let Listing { mut extents, comparison, .. } =
+            listing.into_inner().unwrap();
+        if let Some(comparison) = comparison {
+            return Ok(comparison);
+        }
+        if extents.is_empty() {
+            return Ok(Comparison::Same);
+        }
+        let truncated = extents.len() > differences_max;
+        extents.truncate(differences_max);
+        Ok(Comparison::DifferentExtents {
+            left: self.to_path.clone(),
+            right: file_path.into(),
+            extents,
+            truncated,
+        })
+    }
+
+    /// Split the files into chunks, and call `f` with the bounds of each chunk
+    /// and a pair of buffers from a pool of threads.  Chunks are handed out in
+    /// offset order, and none starting at or after `stop` are handed out.
+    fn scan_chunks<F>(&self, stop: &atomic::AtomicU64, f: F) -> Res<()>
+        where F: Fn(u64, u64, &mut [u8], &mut [u8]) -> Res<()> + Sync
+    {
+        let buffer_size = self.options.buffer_size;
+        let chunk_len = buffer_size as u64 * CHUNK_BLOCKS;
+        let next = atomic::AtomicU64::new(0);
+        let work = || -> Res<()> {
+            let mut buf_to = vec![0; buffer_size];
+            let mut buf_file = vec![0; buffer_size];
+            loop {
+                let beg = next.fetch_add(chunk_len, atomic::Ordering::Relaxed);
+                if beg >= self.to_len ||
+                    beg >= stop.load(atomic::Ordering::Relaxed)
+                {
+                    break Ok(());
+                }
+                let end = cmp::min(beg + chunk_len, self.to_len);
+                if let Err(e) = f(beg, end, &mut buf_to, &mut buf_file) {
+                    // no other thread needs to continue
+                    stop.store(0, atomic::Ordering::Relaxed);
+                    break Err(e);
+                }
+            }
+        };
+
+        if self.threads == 1 {
+            // 1 thread: do it on the current thread; do not spawn any new ones
+            return work();
+        }
+        thread::scope(|scope| {
+            let workers = (0..self.threads)
+                .map(|_| scope.spawn(work))
+                .collect::<Vec<_>>();
+            workers.into_iter().try_for_each(|worker| {
+                worker.join().expect("worker thread panicked")
+            })
+        })
+    }
+
+    /// The comparison saying that `file` is the same file as the one
+    /// compared to, e.g. a hard link to it, and so has the same contents, if
+    /// it is.
+    fn same_file(&self, file: &Source, file_path: &path::Path) ->
+        Option<Comparison>
+    {
+        if file.id != self.to.id ||
+            self.options.skip_left != self.options.skip_right
+        {
+            return None;
+        }
+        if self.options.verbose {
+            eprintln!("{:?}: same file as {:?} (device {}, inode {})",
+                      file_path, self.to_path, file.id.0, file.id.1);
+        }
+        Some(Comparison::SameFile { device: file.id.0, inode: file.id.1 })
+    }
+
+    /// Find the ranges at which `file` shares its physical blocks with the
+    /// file compared to, e.g. through reflinks, and so has the same contents.
+    fn shared_ranges(&self, file: &Source, file_path: &path::Path) ->
+        Vec<(u64, u64)>
+    {
+        if file.id.0 != self.to.id.0 {
+            // physical blocks are only comparable on the same device
+            return Vec::new();
+        }
+        let skip = self.options.skip_left;
+        if skip != self.options.skip_right {
+            // and only at the same offsets
+            return Vec::new();
+        }
+        let shared = fiemap::shared_ranges(&self.to.shared, &file.shared)
+            .into_iter()
+            .map(|(beg, end)| {
+                (beg.saturating_sub(skip), end.saturating_sub(skip))
+            })
+            .filter(|&(beg, end)| beg < end)
+            .collect::<Vec<_>>();
+        if self.options.verbose && !shared.is_empty() {
+            let len: u64 = shared.iter().map(|&(beg, end)| end - beg).sum();
+            eprintln!("{:?}: {} B shared with {:?}, not compared",
+                      file_path, len, self.to_path);
+        }
+        shared
+    }
+
+    /// Find where to compare up to from `pos`, at most a buffer's worth and not
+    /// past `end`, without crossing the start or end of a hole in either file
+    /// or of a `shared` range, and whether that need not be compared at all,
+    /// since both files have a hole there or it is shared.
+    fn step(&self, file: &Source, shared: &[(u64, u64)], pos: u64, end: u64) ->
+        (u64, bool)
+    {
+        let (skip_to, skip_file) =
+            (self.options.skip_left, self.options.skip_right);
+        let (hole_to, end_to) = region(&self.to.holes, skip_to + pos);
+        let (hole_file, end_file) = region(&file.holes, skip_file + pos);
+        let end_to = end_to.saturating_sub(skip_to);
+        let end_file = end_file.saturating_sub(skip_file);
+        let (is_shared, end_shared) = region(shared, pos);
+        let end = cmp::min(end, cmp::min(end_shared, cmp::min(end_to,
+                                                              end_file)));
+        if (hole_to && hole_file) || is_shared {
+            (end, true)
+        } else {
+            (cmp::min(end, pos + self.options.buffer_size as u64), false)
+        }
+    }
+
+    /// Get as many bytes at `pos` as the buffers hold, from both files; if
+    /// either file ends before that, return `None`.
+    fn read_at<'a>(&'a self, file: &'a Source, file_path: &path::Path,
+                   pos: u64, buf_to: &'a mut [u8], buf_file: &'a mut [u8]) ->
+        Res<Option<(&'a [u8], &'a [u8])>>
+    {
+        let read_to = self.to.read_at(self.options.skip_left + pos, buf_to);
+        let read_file = file.read_at(self.options.skip_right + pos, buf_file);
+        match (read_to, read_file) {
+            (Ok(bytes_to), Ok(bytes_file)) => Ok(Some((bytes_to, bytes_file))),
+            (Err(ref e), _) | (_, Err(ref e))
+                if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            (Err(e), _) => Err((e, &self.to_path).into()),
+            (_, Err(e)) => Err((e, file_path).into()),
+        }
+    }
+
+    /// The comparison of files which ended at different positions.
+    fn different_size(&self, file_path: &path::Path) -> Res<Comparison> {
+        Ok(Comparison::DifferentSize {
+            left: self.to_path.clone(),
+            len_left: self.to_path.metadata()
+                .map_err(|e| (e, &self.to_path))?
+                .len(),
+            right: file_path.into(),
+            len_right: file_path.metadata()
+                .map_err(|e| (e, file_path))?
+                .len(),
+        })
+    }
+}
+
+/// A file being compared, along with the holes in it if it is sparse, and its
+/// extents shared with other files.
+struct Source {
+    reader: Reader,
+    /// The device and inode numbers.
+    id: (u64, u64),
+    /// The start and end of each hole, in offset order.
+    holes: Vec<(u64, u64)>,
+    shared: Vec<fiemap::SharedExtent>,
+}
+
+/// How a file is read, either memory-mapped or into buffers.
+enum Reader {
+    Mapped(memmap2::Mmap),
+    Read(fs::File),
+}
+
+impl Source {
+    /// Open the file at `path`; its extents shared with other files are only
+    /// looked up if it is on `device`, when given, as files on other devices
+    /// cannot share any with it.
+    fn open(path: &path::Path, device: Option<u64>, options: &Options) ->
+        Res<Self>
+    {
+        let file = fs::File::open(path).map_err(|e| (e, path))?;
+        let metadata = file.metadata().map_err(|e| (e, path))?;
+
+        // a file with fewer blocks allocated than its size needs is sparse
+        let holes = if metadata.blocks() * 512 < metadata.len() {
+            let holes = Self::holes(&file, metadata.len())
+                .map_err(|e| (e, path))?;
+            if options.verbose {
+                eprintln!("{:?}: sparse, {} holes", path, holes.len());
+            }
+            holes
+        } else {
+            Vec::new()
+        };
+        let id = (metadata.dev(), metadata.ino());
+        let shared = if device.is_none_or(|device| device == id.0) {
+            fiemap::shared_extents(&file).map_err(|e| (e, path))?
+        } else {
+            Vec::new()
+        };
+
+        if !options.mmap {
+            if options.verbose {
+                eprintln!("{:?}: reading into buffers", path);
+            }
+            return Ok(Self { reader: Reader::Read(file), id, holes, shared });
+        }
+        let reader = match Self::map(&file) {
+            Ok(mmap) => {
+                if options.verbose {
+                    eprintln!("{:?}: memory-mapped", path);
+                }
+                Reader::Mapped(mmap)
+            },
+            Err(e) => {
+                if options.verbose {
+                    eprintln!("{:?}: cannot memory-map, reading into buffers: \
+                               {}", path, e);
+                }
+                Reader::Read(file)
+            },
+        };
+        Ok(Self { reader, id, holes, shared })
+    }
+
+    fn map(file: &fs::File) -> io::Result<memmap2::Mmap> {
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::other("not a regular file"));
+        }
+        // the map is only ever read from; if another process truncates the
+        // file while it is mapped, reading past its new end raises SIGBUS, as
+        // with any other program using mmap
+        let mmap = unsafe { memmap2::Mmap::map(file)? };
+        // the advice is only a hint, so failing to give it is harmless
+        let _ = mmap.advise(memmap2::Advice::Sequential);
+        Ok(mmap)
+    }
+
+    /// Find the holes in the first `len` bytes of `file` with
+    /// `lseek(SEEK_HOLE)` and `lseek(SEEK_DATA)`.
+    fn holes(file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        let fd = file.as_raw_fd();
+        let seek = |pos: u64, whence| -> io::Result<Option<u64>> {
+            match unsafe { libc::lseek(fd, pos as libc::off_t, whence) } {
+                -1 => match io::Error::last_os_error() {
+                    // no more data or holes after `pos`
+                    ref e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                    e => Err(e),
+                },
+                pos => Ok(Some(pos as u64)),
+            }
+        };
+
+        let mut holes = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let beg = match seek(pos, libc::SEEK_HOLE)? {
+                Some(beg) if beg < len => beg,
+                _ => break,
+            };
+            let end = seek(beg, libc::SEEK_DATA)?.map_or(len, |end| {
+                cmp::min(end, len)
+            });
+            holes.push((beg, end));
+            pos = end;
+        }
+        Ok(holes)
+    }
+
+    /// Get `buf.len()` bytes at `pos`, either directly from the map or by
+    /// reading them into `buf`; the bytes must be either all in a hole or all
+    /// outside of one.
+    fn read_at<'a>(&'a self, pos: u64, buf: &'a mut [u8]) ->
+        io::Result<&'a [u8]>
+    {
+        if let (true, _) = region(&self.holes, pos) {
+            buf.fill(0);
+            return Ok(buf);
+        }
+        match self.reader {
+            Reader::Mapped(ref mmap) => {
+                let beg = pos as usize;
+                mmap.get(beg..beg + buf.len()).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof,
+                                   "failed to fill whole buffer")
+                })
+            },
+            Reader::Read(ref file) => {
+                file.read_exact_at(buf, pos)?;
+                Ok(buf)
+            },
+        }
+    }
+}
+
+/// Whether `pos` is in one of `ranges`, which are in offset order, and where
+/// the range or the gap between ranges containing it ends.
+fn region(ranges: &[(u64, u64)], pos: u64) -> (bool, u64) {
+    let i = ranges.partition_point(|&(beg, _)| beg <= pos);
+    match i.checked_sub(1).map(|i| ranges[i]) {
+        Some((_, end)) if pos < end => (true, end),
+        _ => (false, ranges.get(i).map_or(u64::MAX, |&(beg, _)| beg)),
+    }
+}
+
+/// The result of comparing a sampled block.
+enum Sampled {
+    Same,
+    /// The offset of the first difference, and the byte in each file there.
+    Different(u64, u8, u8),
+    /// Either file ended before the block did.
+    Ended,
+}
+
+/// The differing extents found by the chunks of a listing so far.
+#[derive(Default)]
+struct Listing {
+    /// The chunks not yet merged into `extents`, by their start.
+    chunks: BTreeMap<u64, (u64, Vec<Extent>)>,
+    /// The end of the chunks merged into `extents`.
+    done: u64,
+    extents: Vec<Extent>,
+    comparison: Option<Comparison>,
+}
+
+impl Listing {
+    /// Merge the chunks directly following the ones already merged, joining
+    /// extents which touch or overlap.
+    fn merge_done(&mut self) {
+        while let Some((end, extents)) = self.chunks.remove(&self.done) {
+            for extent in extents {
+                if let Some(last) = self.extents.last_mut() {
+                    if last.offset + last.len >= extent.offset {
+                        last.len = cmp::max(last.len,
+                                            extent.offset + extent.len -
+                                            last.offset);
+                        continue;
+                    }
+                }
+                self.extents.push(extent);
+            }
+            self.done = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// Add a chunk from `beg` to `end` with `extents` to `listing`, and
+    /// return the extents merged so far.
+    fn add(listing: &mut Listing, beg: u64, end: u64, extents: &[(u64, u64)])
+        -> Vec<(u64, u64)>
+    {
+        let extents = extents.iter()
+            .map(|&(offset, len)| Extent { offset, len })
+            .collect();
+        listing.chunks.insert(beg, (end, extents));
+        listing.merge_done();
+        listing.extents.iter()
+            .map(|extent| (extent.offset, extent.len))
+            .collect()
+    }
+
+    #[test]
+    fn merge_done_in_order() {
+        let mut listing = Listing::default();
+        assert_eq!(add(&mut listing, 0, 10, &[(2, 3), (5, 5)]), [(2, 8)]);
+        assert_eq!(add(&mut listing, 10, 20, &[(10, 2), (15, 1)]),
+                   [(2, 10), (15, 1)]);
+        assert_eq!(add(&mut listing, 20, 30, &[]), [(2, 10), (15, 1)]);
+        assert_eq!(add(&mut listing, 30, 40, &[(31, 9)]),
+                   [(2, 10), (15, 1), (31, 9)]);
+        assert_eq!(listing.done, 40);
+    }
+
+    #[test]
+    fn merge_done_out_of_order() {
+        let mut listing = Listing::default();
+        assert_eq!(add(&mut listing, 20, 30, &[(20, 10)]), []);
+        assert_eq!(add(&mut listing, 10, 20, &[(18, 2)]), []);
+        assert_eq!(listing.done, 0);
+        assert_eq!(add(&mut listing, 0, 10, &[(0, 1), (9, 1)]),
+                   [(0, 1), (9, 1), (18, 12)]);
+        assert_eq!(listing.done, 30);
+    }
+
+    #[test]
+    fn merge_done_overlapping() {
+        let mut listing = Listing::default();
+        assert_eq!(add(&mut listing, 0, 10, &[(0, 6), (4, 4), (5, 1)]),
+                   [(0, 8)]);
+        assert_eq!(add(&mut listing, 10, 20, &[(7, 5)]), [(0, 12)]);
+    }
+
+    /// A file with `holes`, which is never read.
+    fn source(holes: &[(u64, u64)]) -> Source {
+        Source {
+            reader: Reader::Read(fs::File::open("/dev/null").unwrap()),
+            id: (0, 0),
+            holes: holes.to_vec(),
+            shared: Vec::new(),
+        }
+    }
+
+    /// A comparer to a file with `holes`, with 10-byte buffers and skipping
+    /// `skip_left` and `skip_right` bytes.
+    fn comparer(holes: &[(u64, u64)], skip_left: u64, skip_right: u64) ->
+        Comparer
+    {
+        Comparer {
+            to_path: "to".into(),
+            to: source(holes),
+            to_len: 1000,
+            threads: 1,
+            options: Options {
+                buffer_size: 10,
+                skip_left,
+                skip_right,
+                ..Options::default()
+            },
+        }
+    }
+
+    #[test]
+    fn region_positions() {
+        let ranges = [(10, 20), (30, 40)];
+        assert_eq!(region(&ranges, 0), (false, 10));
+        assert_eq!(region(&ranges, 10), (true, 20));
+        assert_eq!(region(&ranges, 19), (true, 20));
+        assert_eq!(region(&ranges, 20), (false, 30));
+        assert_eq!(region(&ranges, 25), (false, 30));
+        assert_eq!(region(&ranges, 35), (true, 40));
+        assert_eq!(region(&ranges, 40), (false, u64::MAX));
+        assert_eq!(region(&ranges, 100), (false, u64::MAX));
+        assert_eq!(region(&[], 0), (false, u64::MAX));
+    }
+
+    #[test]
+    fn holes_sparse_file() {
+        let dir = env::temp_dir()
+            .join(format!("same-file-test-{}-holes", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        let file = fs::File::create(&path).unwrap();
+        file.set_len(1 << 20).unwrap();
+        file.write_all_at(&[1; 4096], 1 << 18).unwrap();
+        assert_eq!(Source::holes(&file, 1 << 20).unwrap(),
+                   [(0, 1 << 18), ((1 << 18) + 4096, 1 << 20)]);
+        assert_eq!(Source::holes(&file, 1 << 19).unwrap(),
+                   [(0, 1 << 18), ((1 << 18) + 4096, 1 << 19)]);
+        assert_eq!(Source::holes(&file, 1 << 18).unwrap(), [(0, 1 << 18)]);
+        file.write_all_at(&[1; 4096], 0).unwrap();
+        assert_eq!(Source::holes(&file, 1 << 18).unwrap(), [(4096, 1 << 18)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn step_holes() {
+        let comparer = comparer(&[(20, 40)], 0, 0);
+        let file = source(&[(25, 50)]);
+        // a buffer's worth, then up to the start of a hole
+        assert_eq!(comparer.step(&file, &[], 0, 100), (10, false));
+        assert_eq!(comparer.step(&file, &[], 15, 100), (20, false));
+        // in a hole in one file only, then in both
+        assert_eq!(comparer.step(&file, &[], 20, 100), (25, false));
+        assert_eq!(comparer.step(&file, &[], 25, 100), (40, true));
+        assert_eq!(comparer.step(&file, &[], 30, 35), (35, true));
+        assert_eq!(comparer.step(&file, &[], 40, 100), (50, false));
+        // past the last hole
+        assert_eq!(comparer.step(&file, &[], 50, 100), (60, false));
+        assert_eq!(comparer.step(&file, &[], 95, 100), (100, false));
+    }
+
+    #[test]
+    fn step_shared() {
+        let comparer = comparer(&[], 0, 0);
+        let file = source(&[]);
+        let shared = [(20, 60)];
+        assert_eq!(comparer.step(&file, &shared, 15, 100), (20, false));
+        assert_eq!(comparer.step(&file, &shared, 20, 100), (60, true));
+        assert_eq!(comparer.step(&file, &shared, 20, 50), (50, true));
+        assert_eq!(comparer.step(&file, &shared, 60, 100), (70, false));
+    }
+
+    #[test]
+    fn step_skipped() {
+        // the hole in the file compared to is at 15 to 35 after skipping
+        let skipped = comparer(&[(20, 40)], 5, 0);
+        let file = source(&[(15, 50)]);
+        assert_eq!(skipped.step(&file, &[], 10, 100), (15, false));
+        assert_eq!(skipped.step(&file, &[], 15, 100), (35, true));
+        assert_eq!(skipped.step(&file, &[], 35, 100), (45, false));
+
+        // skipping into the hole, and past it
+        let file = source(&[(0, 20)]);
+        assert_eq!(comparer(&[(20, 40)], 30, 0).step(&file, &[], 0, 100),
+                   (10, true));
+        assert_eq!(comparer(&[(20, 40)], 50, 0).step(&file, &[], 0, 100),
+                   (10, false));
+        // and in the other file
+        let file = source(&[(25, 40)]);
+        assert_eq!(comparer(&[], 0, 20).step(&file, &[], 0, 100),
+                   (5, false));
+        assert_eq!(comparer(&[(5, 20)], 0, 20).step(&file, &[], 5, 100),
+                   (20, true));
+    }
+}
@@ -0,0 +1,249 @@
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use serde_json::Value;
+use std::{fmt, fs, io, path, process};
+use libc;
+use output::{path_to_json, ToJson};
+use {compare_files, keep_going, stream, Comparison, Error, Options, Res};
+
+arg_enum!{
+    #[derive(Clone, Copy, Debug)]
+    pub enum Method {
+        Hardlink,
+        Reflink
+    }
+}
+
+/// Replace each file in `paths` but the first with a hard link or reflink to
+/// the first one; all of them must have been found to have the same contents.
+pub fn dedupe(paths: &[&path::Path], method: Method, dry_run: bool,
+              options: &Options, errors: &mut Vec<Error>) ->
+    Res<Vec<Deduplication>>
+{
+    let (keep, rest) = paths.split_at(1);
+    let keep = *keep.first().expect("no first element in keep");
+    let metadata_keep = if stream::is_stdin(keep) {
+        None
+    } else {
+        let metadata = fs::metadata(keep).map_err(|e| Error::from((e, keep)));
+        match keep_going(metadata, options, errors)? {
+            Some(metadata) => Some(metadata),
+            None => return Ok(Vec::new()),
+        }
+    };
+
+    let mut deduplications = Vec::new();
+    for &path in rest {
+        let outcome = match metadata_keep {
+            Some(ref metadata_keep) if metadata_keep.is_file() => {
+                let outcome = dedupe_file(keep, metadata_keep, path, method,
+                                          dry_run, options);
+                match keep_going(outcome, options, errors)? {
+                    Some(outcome) => outcome,
+                    None => continue,
+                }
+            },
+            _ => Outcome::Skipped("first file not a regular file"),
+        };
+        deduplications.push(Deduplication {
+            keep: keep.into(),
+            path: path.into(),
+            method,
+            outcome,
+        });
+    }
+    Ok(deduplications)
+}
+
+fn dedupe_file(keep: &path::Path, metadata_keep: &fs::Metadata,
+               path: &path::Path, method: Method, dry_run: bool,
+               options: &Options) -> Res<Outcome>
+{
+    if stream::is_stdin(path) {
+        return Ok(Outcome::Skipped("not a regular file"));
+    }
+    // symbolic links are followed when comparing files, so it is their
+    // targets which are linked to and replaced, not the links themselves
+    let keep = &fs::canonicalize(keep).map_err(|e| (e, keep))?;
+    let path = &fs::canonicalize(path).map_err(|e| (e, path))?;
+    let metadata = fs::metadata(path).map_err(|e| (e, path))?;
+    if !metadata.is_file() {
+        return Ok(Outcome::Skipped("not a regular file"));
+    }
+    if (metadata.dev(), metadata.ino()) ==
+        (metadata_keep.dev(), metadata_keep.ino())
+    {
+        return Ok(Outcome::Skipped("already the same file"));
+    }
+    if metadata.dev() != metadata_keep.dev() {
+        return Ok(Outcome::Skipped("on a different filesystem"));
+    }
+    if let Method::Hardlink = method {
+        // hard links share their permissions and owner, which must not change,
+        // and their timestamps
+        if (metadata.mode(), metadata.uid(), metadata.gid()) !=
+            (metadata_keep.mode(), metadata_keep.uid(), metadata_keep.gid())
+        {
+            return Ok(Outcome::Skipped("different permissions or owner"));
+        }
+        // nor must the modification time, which tells when the contents
+        // changed
+        if (metadata.mtime(), metadata.mtime_nsec()) !=
+            (metadata_keep.mtime(), metadata_keep.mtime_nsec())
+        {
+            return Ok(Outcome::Skipped("different timestamps"));
+        }
+    }
+    if dry_run {
+        return Ok(Outcome::DryRun);
+    }
+
+    let name = path.file_name().expect("no file name in path");
+    let tmp = path.with_file_name(format!(".{}.same-file-{}",
+                                          name.to_string_lossy(),
+                                          process::id()));
+    match method {
+        Method::Hardlink =>
+            fs::hard_link(keep, &tmp).map_err(|e| (e, &tmp))?,
+        Method::Reflink => match reflink(keep, &tmp, &metadata) {
+            Ok(true) => (),
+            Ok(false) => {
+                fs::remove_file(&tmp).map_err(|e| (e, &tmp))?;
+                return Ok(Outcome::Skipped("reflinks not supported"));
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            },
+        },
+    }
+
+    // verify the contents once more right before replacing the file, in case
+    // either file changed since it was compared
+    let comparison = compare_files(&tmp, metadata_keep.len(),
+                                   path, metadata.len(), options);
+    match comparison {
+        Ok(Comparison::Same) => (),
+        Ok(_) => {
+            fs::remove_file(&tmp).map_err(|e| (e, &tmp))?;
+            return Ok(Outcome::Skipped("contents changed since compared"));
+        },
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        },
+    }
+
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err((e, path).into());
+    }
+    Ok(Outcome::Replaced)
+}
+
+/// Create `tmp` as a reflink to `keep` with the `FICLONE` ioctl, with the
+/// permissions, owner and timestamps in `metadata`; return whether the
+/// filesystem supports reflinks between them.
+fn reflink(keep: &path::Path, tmp: &path::Path, metadata: &fs::Metadata) ->
+    Res<bool>
+{
+    // from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    let file_keep = fs::File::open(keep).map_err(|e| (e, keep))?;
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(metadata.mode() & 0o7777)
+        .open(tmp)
+        .map_err(|e| (e, tmp))?;
+
+    let result = unsafe {
+        libc::ioctl(file.as_raw_fd(), FICLONE as _, file_keep.as_raw_fd())
+    };
+    if result == -1 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::EXDEV) =>
+                Ok(false),
+            _ => Err((e, tmp).into()),
+        };
+    }
+
+    let file_metadata = file.metadata().map_err(|e| (e, tmp))?;
+    if (file_metadata.uid(), file_metadata.gid()) !=
+        (metadata.uid(), metadata.gid())
+    {
+        let result = unsafe {
+            libc::fchown(file.as_raw_fd(), metadata.uid(), metadata.gid())
+        };
+        if result == -1 {
+            return Err((io::Error::last_os_error(), tmp).into());
+        }
+    }
+    // set after changing the owner, which may clear the set-user-ID and
+    // set-group-ID bits
+    file.set_permissions(fs::Permissions::from_mode(metadata.mode()))
+        .map_err(|e| (e, tmp))?;
+
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: metadata.atime_nsec() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: metadata.mtime_nsec() as libc::c_long,
+        },
+    ];
+    if unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } == -1 {
+        return Err((io::Error::last_os_error(), tmp).into());
+    }
+    Ok(true)
+}
+
+pub struct Deduplication {
+    keep: path::PathBuf,
+    path: path::PathBuf,
+    method: Method,
+    outcome: Outcome,
+}
+
+enum Outcome {
+    Replaced,
+    DryRun,
+    Skipped(&'static str),
+}
+
+impl fmt::Display for Deduplication {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = match (self.method, &self.outcome) {
+            (Method::Hardlink, Outcome::Replaced) => "hard-linked",
+            (Method::Hardlink, Outcome::DryRun) => "would hard-link",
+            (Method::Reflink, Outcome::Replaced) => "reflinked",
+            (Method::Reflink, Outcome::DryRun) => "would reflink",
+            (_, Outcome::Skipped(reason)) =>
+                return write!(f, "not deduplicated: {:?}: {}",
+                              self.path, reason),
+        };
+        write!(f, "{} {:?} to {:?}", verb, self.path, self.keep)
+    }
+}
+
+impl ToJson for Deduplication {
+    fn to_json(&self) -> Vec<Value> {
+        let (outcome, reason) = match self.outcome {
+            Outcome::Replaced => ("replaced", None),
+            Outcome::DryRun => ("dry_run", None),
+            Outcome::Skipped(reason) => ("skipped", Some(reason)),
+        };
+        vec![json!({
+            "kind": "deduplication",
+            "method": self.method.to_string().to_lowercase(),
+            "keep": path_to_json(&self.keep),
+            "path": path_to_json(&self.path),
+            "outcome": outcome,
+            "reason": reason,
+        })]
+    }
+}
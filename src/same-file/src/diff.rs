@@ -0,0 +1,518 @@
+use serde_json::Value;
+use std::{cmp, fmt, fs, path, str};
+use output::{path_to_json, size_to_json, ToJson};
+use {stream, Comparison, Options, Res};
+
+/// The most edits searched for from each end of two files, before giving up
+/// on diffing them, as the time taken grows with its square.
+const EDITS_MAX: isize = 1000;
+
+/// The lines of two text files which differ, as the hunks of a unified diff.
+pub struct Diff {
+    left: path::PathBuf,
+    right: path::PathBuf,
+    hunks: Vec<Hunk>,
+    truncated: bool,
+}
+
+/// Consecutive lines, with their line endings, of which some differ, along
+/// with where they start in each file and how many there are.
+struct Hunk {
+    beg_left: usize,
+    len_left: usize,
+    beg_right: usize,
+    len_right: usize,
+    lines: Vec<(Op, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// An operation turning the left file into the right one, at the given lines
+/// of each file.
+struct Edit {
+    op: Op,
+    left: usize,
+    right: usize,
+}
+
+impl Diff {
+    /// Diff the files which `comparison` found to have different contents,
+    /// with `context` lines around each change and up to `hunks_max` hunks, if
+    /// both look like UTF-8 text and can be read again.
+    pub fn new(comparison: &Comparison, context: usize, hunks_max: usize,
+               options: &Options) -> Res<Option<Self>>
+    {
+        let (left, right) = match comparison {
+            Comparison::DifferentSize { left, right, .. } |
+            Comparison::DifferentContents { left, right, .. } |
+            Comparison::DifferentExtents { left, right, .. } |
+            Comparison::DifferentHash { left, right } |
+            Comparison::DifferentSample { left, right, .. } => (left, right),
+            _ => return Ok(None),
+        };
+        if stream::is_stream_path(left) || stream::is_stream_path(right) {
+            // the bytes read from a stream are gone
+            if options.verbose {
+                eprintln!("{:?} -- {:?}: streams cannot be diffed",
+                          left, right);
+            }
+            return Ok(None);
+        }
+        let text_left = fs::read(left).map_err(|e| (e, left))?;
+        let text_right = fs::read(right).map_err(|e| (e, right))?;
+        let (text_left, text_right) =
+            match (to_text(&text_left), to_text(&text_right)) {
+                (Some(text_left), Some(text_right)) => (text_left, text_right),
+                _ => {
+                    if options.verbose {
+                        eprintln!("{:?} -- {:?}: not text, not diffed",
+                                  left, right);
+                    }
+                    return Ok(None);
+                },
+            };
+
+        let lines_left = text_left.split_inclusive('\n').collect::<Vec<_>>();
+        let lines_right = text_right.split_inclusive('\n').collect::<Vec<_>>();
+        let edits = match edits(&lines_left, &lines_right) {
+            Some(edits) => edits,
+            None => {
+                if options.verbose {
+                    eprintln!("{:?} -- {:?}: too many changes, not diffed",
+                              left, right);
+                }
+                return Ok(None);
+            },
+        };
+        let (hunks, truncated) = hunks(&edits, context, hunks_max);
+        let hunks = hunks.into_iter()
+            .map(|edits| Hunk::new(edits, &lines_left, &lines_right))
+            .collect();
+        Ok(Some(Diff {
+            left: left.clone(),
+            right: right.clone(),
+            hunks,
+            truncated,
+        }))
+    }
+}
+
+/// The contents of a file as text, unless they are not UTF-8 or have NUL
+/// bytes, which do not occur in text files.
+fn to_text(bytes: &[u8]) -> Option<&str> {
+    if bytes.contains(&0) {
+        return None;
+    }
+    str::from_utf8(bytes).ok()
+}
+
+/// Find the shortest edit script turning `left` into `right`, with the
+/// variant of the algorithm of Myers which takes linear space, and with the
+/// lines deleted before those inserted in their place, unless it would take
+/// too many edits.
+fn edits(left: &[&str], right: &[&str]) -> Option<Vec<Edit>> {
+    let max = (left.len() + right.len()).div_ceil(2) + 1;
+    let mut v = (vec![0; 2 * max + 1], vec![0; 2 * max + 1]);
+    let mut edits = Vec::with_capacity(cmp::max(left.len(), right.len()));
+    split(left, right, (0, 0), &mut v, &mut edits)?;
+
+    let mut beg = 0;
+    while let Some(first) = edits[beg..].iter().position(|e| e.op != Op::Equal)
+    {
+        beg += first;
+        let end = edits[beg..].iter().position(|e| e.op == Op::Equal)
+            .map_or(edits.len(), |len| beg + len);
+        let (left, right) = (edits[beg].left, edits[beg].right);
+        let deleted = edits[beg..end].iter()
+            .filter(|e| e.op == Op::Delete)
+            .count();
+        for (i, edit) in edits[beg..end].iter_mut().enumerate() {
+            *edit = if i < deleted {
+                Edit { op: Op::Delete, left: left + i, right }
+            } else {
+                Edit { op: Op::Insert, left: left + deleted,
+                       right: right + i - deleted }
+            };
+        }
+        beg = end;
+    }
+    Some(edits)
+}
+
+/// Append the edits turning `left` into `right`, which start at the lines
+/// `beg` of each file, to `edits`: the lines they start and end with in
+/// common are set aside, and the rest is split at the middle snake.
+fn split(left: &[&str], right: &[&str], beg: (usize, usize),
+         v: &mut (Vec<isize>, Vec<isize>), edits: &mut Vec<Edit>) ->
+    Option<()>
+{
+    let prefix = left.iter().zip(right)
+        .take_while(|&(l, r)| l == r)
+        .count();
+    edits.extend((0..prefix).map(|i| Edit {
+        op: Op::Equal, left: beg.0 + i, right: beg.1 + i,
+    }));
+    let (left, right) = (&left[prefix..], &right[prefix..]);
+    let suffix = left.iter().rev().zip(right.iter().rev())
+        .take_while(|&(l, r)| l == r)
+        .count();
+    let (left, right) = (&left[..left.len() - suffix],
+                         &right[..right.len() - suffix]);
+    let beg = (beg.0 + prefix, beg.1 + prefix);
+
+    if left.is_empty() || right.is_empty() {
+        edits.extend((0..left.len()).map(|i| Edit {
+            op: Op::Delete, left: beg.0 + i, right: beg.1,
+        }));
+        edits.extend((0..right.len()).map(|i| Edit {
+            op: Op::Insert, left: beg.0, right: beg.1 + i,
+        }));
+    } else {
+        let (x, y) = middle_snake(left, right, v)?;
+        split(&left[..x], &right[..y], beg, v, edits)?;
+        split(&left[x..], &right[y..], (beg.0 + x, beg.1 + y), v, edits)?;
+    }
+
+    let end = (beg.0 + left.len(), beg.1 + right.len());
+    edits.extend((0..suffix).map(|i| Edit {
+        op: Op::Equal, left: end.0 + i, right: end.1 + i,
+    }));
+    Some(())
+}
+
+/// Find where the middle snake of the shortest edit script turning `left`
+/// into `right` starts, searching from both ends at once until the paths
+/// meet, unless that takes more than `EDITS_MAX` edits from each end; the
+/// files must neither start nor end with the same line.
+fn middle_snake(left: &[&str], right: &[&str],
+                v: &mut (Vec<isize>, Vec<isize>)) -> Option<(usize, usize)>
+{
+    let (n, m) = (left.len() as isize, right.len() as isize);
+    // the number of equal lines from (x, y) on, forward and backward
+    let inside = |x: isize, y: isize| x >= 0 && y >= 0 && x < n && y < m;
+    let snake_fwd = |x: isize, y: isize| if inside(x, y) {
+        left[x as usize..].iter().zip(&right[y as usize..])
+            .take_while(|&(l, r)| l == r)
+            .count() as isize
+    } else {
+        0
+    };
+    let snake_bwd = |x: isize, y: isize| if inside(n - x - 1, m - y - 1) {
+        left[..(n - x) as usize].iter().rev()
+            .zip(right[..(m - y) as usize].iter().rev())
+            .take_while(|&(l, r)| l == r)
+            .count() as isize
+    } else {
+        0
+    };
+
+    // the furthest line reached in `left` on each diagonal k, at index
+    // k + offset, forward from the start and backward from the end, where
+    // diagonal k backward is diagonal delta - k forward
+    let delta = n - m;
+    let odd = delta & 1 == 1;
+    let offset = (n + m + 1) / 2 + 1;
+    let (fwd, bwd) = (&mut v.0, &mut v.1);
+    fwd[offset as usize + 1] = 0;
+    bwd[offset as usize + 1] = 0;
+    for d in 0..cmp::min(offset, EDITS_MAX + 1) {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let x = if k == -d || (k != d && fwd[i - 1] < fwd[i + 1]) {
+                fwd[i + 1]
+            } else {
+                fwd[i - 1] + 1
+            };
+            fwd[i] = x + snake_fwd(x, x - k);
+            if odd && (k - delta).abs() < d &&
+                fwd[i] + bwd[(delta - k + offset) as usize] >= n
+            {
+                return Some((x as usize, (x - k) as usize));
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let x = if k == -d || (k != d && bwd[i - 1] < bwd[i + 1]) {
+                bwd[i + 1]
+            } else {
+                bwd[i - 1] + 1
+            };
+            bwd[i] = x + snake_bwd(x, x - k);
+            if !odd && (k - delta).abs() <= d &&
+                bwd[i] + fwd[(delta - k + offset) as usize] >= n
+            {
+                return Some(((n - bwd[i]) as usize,
+                             (m - bwd[i] + k) as usize));
+            }
+        }
+    }
+    None
+}
+
+/// Group `edits` into hunks of changes with up to `context` lines around
+/// them, merging changes closer than that, and stop after `hunks_max` hunks;
+/// return whether any changes were left out.
+fn hunks(edits: &[Edit], context: usize, hunks_max: usize) ->
+    (Vec<&[Edit]>, bool)
+{
+    let change = |edit: &Edit| edit.op != Op::Equal;
+    let mut hunks = Vec::new();
+    let mut pos = 0;
+    while let Some(first) = edits[pos..].iter().position(change) {
+        if hunks.len() == hunks_max {
+            return (hunks, true);
+        }
+        let first = pos + first;
+        let beg = cmp::max(first.saturating_sub(context), pos);
+        let mut end = first + 1;
+        while let Some(next) = edits[end..].iter().position(change) {
+            if next > context.saturating_mul(2) {
+                break;
+            }
+            end += next + 1;
+        }
+        let end = cmp::min(end.saturating_add(context), edits.len());
+        hunks.push(&edits[beg..end]);
+        pos = end;
+    }
+    (hunks, false)
+}
+
+impl Hunk {
+    fn new(edits: &[Edit], lines_left: &[&str], lines_right: &[&str]) ->
+        Self
+    {
+        let lines = edits.iter()
+            .map(|edit| {
+                let line = match edit.op {
+                    Op::Equal | Op::Delete => lines_left[edit.left],
+                    Op::Insert => lines_right[edit.right],
+                };
+                (edit.op, line.to_string())
+            })
+            .collect::<Vec<_>>();
+        let len = |op| lines.iter().filter(|&&(o, _)| o == Op::Equal || o == op)
+            .count();
+        Hunk {
+            beg_left: edits[0].left,
+            len_left: len(Op::Delete),
+            beg_right: edits[0].right,
+            len_right: len(Op::Insert),
+            lines,
+        }
+    }
+}
+
+impl Op {
+    fn prefix(self) -> char {
+        match self {
+            Op::Equal => ' ',
+            Op::Delete => '-',
+            Op::Insert => '+',
+        }
+    }
+}
+
+/// Format the lines of a hunk starting at index `beg` as in the header of a
+/// unified diff, where an empty range starts at the line before it.
+fn range(beg: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", beg),
+        1 => format!("{}", beg + 1),
+        _ => format!("{},{}", beg + 1, len),
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "--- {}\n+++ {}", self.left.display(), self.right.display())?;
+        for hunk in &self.hunks {
+            write!(f, "\n@@ -{} +{} @@",
+                   range(hunk.beg_left, hunk.len_left),
+                   range(hunk.beg_right, hunk.len_right))?;
+            for (op, line) in &hunk.lines {
+                match line.strip_suffix('\n') {
+                    Some(line) => write!(f, "\n{}{}", op.prefix(), line)?,
+                    None => write!(f, "\n{}{}\n\\ No newline at end of file",
+                                   op.prefix(), line)?,
+                }
+            }
+        }
+        if self.truncated {
+            write!(f, "\n(more hunks not shown)")?;
+        }
+        Ok(())
+    }
+}
+
+impl ToJson for Diff {
+    fn to_json(&self) -> Vec<Value> {
+        vec![json!({
+            "kind": "different_lines",
+            "paths": [path_to_json(&self.left), path_to_json(&self.right)],
+            "sizes": [size_to_json(&self.left), size_to_json(&self.right)],
+            "hunks": self.hunks.iter()
+                .map(|hunk| json!({
+                    "lines": [hunk.beg_left + 1, hunk.beg_right + 1],
+                    "lengths": [hunk.len_left, hunk.len_right],
+                    "diff": hunk.lines.iter()
+                        .map(|(op, line)| format!("{}{}", op.prefix(), line))
+                        .collect::<Vec<_>>(),
+                }))
+                .collect::<Vec<_>>(),
+            "truncated": self.truncated,
+        })]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that `edits` turns `left` into `right` with as few changes as
+    /// there can be, which is found from their longest common subsequence.
+    fn check(left: &[&str], right: &[&str]) {
+        let edits = edits(left, right).unwrap();
+        let (mut x, mut y) = (0, 0);
+        for edit in &edits {
+            assert_eq!((edit.left, edit.right), (x, y));
+            match edit.op {
+                Op::Equal => {
+                    assert_eq!(left[x], right[y]);
+                    x += 1;
+                    y += 1;
+                },
+                Op::Delete => x += 1,
+                Op::Insert => y += 1,
+            }
+        }
+        assert_eq!((x, y), (left.len(), right.len()));
+
+        let mut lcs = vec![vec![0; right.len() + 1]; left.len() + 1];
+        for i in 0..left.len() {
+            for j in 0..right.len() {
+                lcs[i + 1][j + 1] = if left[i] == right[j] {
+                    lcs[i][j] + 1
+                } else {
+                    cmp::max(lcs[i][j + 1], lcs[i + 1][j])
+                };
+            }
+        }
+        let changes = edits.iter().filter(|edit| edit.op != Op::Equal).count();
+        assert_eq!(changes,
+                   left.len() + right.len() - 2 * lcs[left.len()][right.len()]);
+    }
+
+    #[test]
+    fn edits_empty() {
+        check(&[], &[]);
+        check(&[], &["a\n", "b\n"]);
+        check(&["a\n", "b\n"], &[]);
+    }
+
+    #[test]
+    fn edits_shortest() {
+        check(&["a\n", "b\n", "c\n"], &["a\n", "x\n", "c\n"]);
+        check(&["a\n", "b\n", "c\n", "a\n", "b\n", "b\n", "a\n"],
+              &["c\n", "b\n", "a\n", "b\n", "a\n", "c\n"]);
+        check(&["a\n", "b"], &["a\n", "b\n"]);
+
+        // lines drawn from a few, so that many of them are the same
+        let lines = ["a\n", "b\n", "c\n", "d"];
+        let mut seed = 1u64;
+        let mut next = |max: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as usize % max
+        };
+        for _ in 0..1000 {
+            let left = (0..next(40)).map(|_| lines[next(4)])
+                .collect::<Vec<_>>();
+            let right = (0..next(40)).map(|_| lines[next(4)])
+                .collect::<Vec<_>>();
+            check(&left, &right);
+        }
+    }
+
+    #[test]
+    fn edits_too_many() {
+        let lines = (0..4000).map(|i| format!("{}\n", i)).collect::<Vec<_>>();
+        let lines = lines.iter().map(|line| line.as_str()).collect::<Vec<_>>();
+        assert!(edits(&lines[..2000], &lines[2000..]).is_none());
+        check(&lines[..1000], &lines[3000..]);
+        check(&lines[..3000], &lines[..2000]);
+    }
+
+    /// Edits changing the lines at `changed` of `len` equal ones.
+    fn changes(len: usize, changed: &[usize]) -> Vec<Edit> {
+        (0..len)
+            .map(|i| Edit {
+                op: if changed.contains(&i) { Op::Delete } else { Op::Equal },
+                left: i,
+                right: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hunks_context() {
+        let bounds = |edits: &[Edit], context, hunks_max| {
+            let (hunks, truncated) = hunks(edits, context, hunks_max);
+            (hunks.iter()
+                 .map(|hunk| (hunk[0].left, hunk[hunk.len() - 1].left))
+                 .collect::<Vec<_>>(),
+             truncated)
+        };
+        assert_eq!(bounds(&changes(20, &[]), 3, 10), (vec![], false));
+        assert_eq!(bounds(&changes(20, &[0, 19]), 3, 10),
+                   (vec![(0, 3), (16, 19)], false));
+        assert_eq!(bounds(&changes(20, &[5, 11]), 3, 10),
+                   (vec![(2, 14)], false));
+        assert_eq!(bounds(&changes(20, &[5, 12]), 3, 10),
+                   (vec![(2, 15)], false));
+        assert_eq!(bounds(&changes(20, &[5, 13]), 3, 10),
+                   (vec![(2, 8), (10, 16)], false));
+        assert_eq!(bounds(&changes(20, &[5, 12]), 0, 10),
+                   (vec![(5, 5), (12, 12)], false));
+        assert_eq!(bounds(&changes(20, &[5, 13]), 3, 1),
+                   (vec![(2, 8)], true));
+    }
+
+    /// The unified diff of `left` and `right`.
+    fn diff(left: &str, right: &str) -> String {
+        let lines_left = left.split_inclusive('\n').collect::<Vec<_>>();
+        let lines_right = right.split_inclusive('\n').collect::<Vec<_>>();
+        let edits = edits(&lines_left, &lines_right).unwrap();
+        let (hunks, truncated) = hunks(&edits, 3, 10);
+        Diff {
+            left: path::PathBuf::from("l"),
+            right: path::PathBuf::from("r"),
+            hunks: hunks.into_iter()
+                .map(|edits| Hunk::new(edits, &lines_left, &lines_right))
+                .collect(),
+            truncated,
+        }.to_string()
+    }
+
+    #[test]
+    fn unified() {
+        assert_eq!(diff("", "a\nb\n"), "--- l\n+++ r\n@@ -0,0 +1,2 @@\n+a\n+b");
+        assert_eq!(diff("a\n", ""), "--- l\n+++ r\n@@ -1 +0,0 @@\n-a");
+        assert_eq!(diff("a\nb\nc\n", "a\nx\nc\n"),
+                   "--- l\n+++ r\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c");
+    }
+
+    #[test]
+    fn unified_no_newline() {
+        assert_eq!(diff("a\nb", "a\nb\n"),
+                   "--- l\n+++ r\n@@ -1,2 +1,2 @@\n a\n-b\n\
+                    \\ No newline at end of file\n+b");
+        assert_eq!(diff("a\n", "a"),
+                   "--- l\n+++ r\n@@ -1 +1 @@\n-a\n+a\n\
+                    \\ No newline at end of file");
+    }
+}
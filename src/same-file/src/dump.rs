@@ -0,0 +1,174 @@
+use serde_json::Value;
+use std::io::prelude::*;
+use std::{cmp, fmt, fs, io, path};
+use hash::to_hex;
+use output::{path_to_json, ToJson};
+use {stream, Comparison, Options, Res};
+
+/// The number of bytes dumped on each row, for each file.
+const ROW_LEN: u64 = 8;
+/// The escape sequences around differing bytes when they are highlighted.
+const HIGHLIGHT: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// The bytes of two files around the ranges at which they differ, printed as
+/// a side-by-side hex and ASCII dump.
+pub struct Dump {
+    left: path::PathBuf,
+    right: path::PathBuf,
+    windows: Vec<Window>,
+    highlight: bool,
+}
+
+/// A range of bytes read from both files, shorter on either side if that file
+/// ends within it.
+struct Window {
+    offset: u64,
+    left: Vec<u8>,
+    right: Vec<u8>,
+}
+
+impl Dump {
+    /// Dump `context` bytes around each difference found in `comparison`, if
+    /// it found any in the contents and both files can be read again; with
+    /// `highlight`, the differing bytes are highlighted.
+    pub fn new(comparison: &Comparison, context: u64, highlight: bool,
+               options: &Options) -> Res<Option<Self>>
+    {
+        let (left, right, ranges) = match comparison {
+            Comparison::DifferentContents { left, right, offset, .. } |
+            Comparison::DifferentSample { left, right, offset, .. } =>
+                (left, right, vec![(*offset, offset + 1)]),
+            Comparison::DifferentExtents { left, right, extents, .. } =>
+                (left, right, extents.iter()
+                    .map(|extent| (extent.offset, extent.offset + extent.len))
+                    .collect()),
+            _ => return Ok(None),
+        };
+        if stream::is_stream_path(left) || stream::is_stream_path(right) {
+            // the bytes read from a stream are gone
+            if options.verbose {
+                eprintln!("{:?} -- {:?}: streams cannot be dumped",
+                          left, right);
+            }
+            return Ok(None);
+        }
+
+        // whole rows around each range, merged where they overlap
+        let mut bounds: Vec<(u64, u64)> = Vec::new();
+        for (beg, end) in ranges {
+            let beg = beg.saturating_sub(context) / ROW_LEN * ROW_LEN;
+            let end = end.saturating_add(context).div_ceil(ROW_LEN) * ROW_LEN;
+            match bounds.last_mut() {
+                Some(last) if last.1 >= beg => last.1 = cmp::max(last.1, end),
+                _ => bounds.push((beg, end)),
+            }
+        }
+        if let Some(bytes) = options.bytes {
+            bounds.retain(|&(beg, _)| beg < bytes);
+            for bound in &mut bounds {
+                bound.1 = cmp::min(bound.1, bytes);
+            }
+        }
+
+        let mut file_left = fs::File::open(left).map_err(|e| (e, left))?;
+        let mut file_right = fs::File::open(right).map_err(|e| (e, right))?;
+        let mut windows = Vec::with_capacity(bounds.len());
+        for (beg, end) in bounds {
+            windows.push(Window {
+                offset: beg,
+                left: read_range(&mut file_left, options.skip_left + beg,
+                                 end - beg).map_err(|e| (e, left))?,
+                right: read_range(&mut file_right, options.skip_right + beg,
+                                  end - beg).map_err(|e| (e, right))?,
+            });
+        }
+        Ok(Some(Dump {
+            left: left.clone(),
+            right: right.clone(),
+            windows,
+            highlight,
+        }))
+    }
+
+    /// Write one side of a row: the bytes in hex, then as ASCII.
+    fn fmt_side(&self, f: &mut fmt::Formatter, bytes: &[u8], other: &[u8]) ->
+        fmt::Result
+    {
+        let differs = |i: usize| other.get(i) != bytes.get(i);
+        for i in 0..ROW_LEN as usize {
+            match bytes.get(i) {
+                Some(byte) if self.highlight && differs(i) =>
+                    write!(f, " {}{:02x}{}", HIGHLIGHT, byte, RESET)?,
+                Some(byte) => write!(f, " {:02x}", byte)?,
+                None => write!(f, "   ")?,
+            }
+        }
+        write!(f, "  |")?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            if self.highlight && differs(i) {
+                write!(f, "{}{}{}", HIGHLIGHT, c, RESET)?;
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+        write!(f, "|")
+    }
+}
+
+/// Read up to `len` bytes of `file` at `pos`, fewer if it ends before that.
+fn read_range(file: &mut fs::File, pos: u64, len: u64) -> io::Result<Vec<u8>>
+{
+    let mut bytes = Vec::new();
+    file.seek(io::SeekFrom::Start(pos))?;
+    file.take(len).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl fmt::Display for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} -- {:?}:", self.left, self.right)?;
+        for (i, window) in self.windows.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n--")?;
+            }
+            let len = cmp::max(window.left.len(), window.right.len());
+            for row in (0..len).step_by(ROW_LEN as usize) {
+                let (left, right) =
+                    (row_of(&window.left, row), row_of(&window.right, row));
+                write!(f, "\n{:08x} ", window.offset + row as u64)?;
+                self.fmt_side(f, left, right)?;
+                // padded to the full width of a row
+                write!(f, "{:1$}  ", "", ROW_LEN as usize - left.len())?;
+                self.fmt_side(f, right, left)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bytes of the row starting at `row` in `bytes`, if any.
+fn row_of(bytes: &[u8], row: usize) -> &[u8] {
+    let end = cmp::min(row + ROW_LEN as usize, bytes.len());
+    bytes.get(row..end).unwrap_or(&[])
+}
+
+impl ToJson for Dump {
+    fn to_json(&self) -> Vec<Value> {
+        vec![json!({
+            "kind": "dump",
+            "paths": [path_to_json(&self.left), path_to_json(&self.right)],
+            "windows": self.windows.iter()
+                .map(|window| json!({
+                    "offset": window.offset,
+                    "bytes": [to_hex(&window.left), to_hex(&window.right)],
+                }))
+                .collect::<Vec<_>>(),
+        })]
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use serde_json::Value;
+use std::io::prelude::*;
+use std::{cmp, fmt, fs, io, path};
+use blake3;
+use cache::{self, Kind};
+use hash::{self, Algorithm};
+use output::{path_to_json, size_to_json, ToJson};
+use {keep_going, tree, Comparer, Comparison, Error, ExitStatus, Options, Res};
+
+pub fn find_duplicates(paths: &[&path::Path], options: &Options,
+                       errors: &mut Vec<Error>) -> Res<Duplicates>
+{
+    let files = collect_files(paths, options, errors)?;
+    let groups = group_identical(files, options, errors)?
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .collect();
+    Ok(Duplicates { groups })
+}
+
+pub fn find_classes(paths: &[&path::Path], options: &Options,
+                    errors: &mut Vec<Error>) -> Res<Classes>
+{
+    let files = collect_files(paths, options, errors)?;
+    let groups = group_identical(files, options, errors)?;
+    Ok(Classes { groups })
+}
+
+/// Expand each directory in `paths` into the regular files below it, and read
+/// the size of every file.
+fn collect_files(paths: &[&path::Path], options: &Options,
+                 errors: &mut Vec<Error>) -> Res<Vec<(path::PathBuf, u64)>>
+{
+    let mut files = BTreeMap::new();
+    for &path in paths {
+        let metadata = fs::metadata(path).map_err(|e| Error::from((e, path)));
+        let metadata = match keep_going(metadata, options, errors)? {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+        if metadata.is_dir() {
+            let entries = tree::walk(path, options, errors);
+            let entries = match keep_going(entries, options, errors)? {
+                Some(entries) => entries,
+                None => continue,
+            };
+            for (relative, file_type) in entries.types {
+                if file_type.is_file() {
+                    let path = path.join(relative);
+                    let metadata = fs::metadata(&path)
+                        .map_err(|e| Error::from((e, &path)));
+                    if let Some(metadata) =
+                        keep_going(metadata, options, errors)?
+                    {
+                        files.insert(path, metadata.len());
+                    }
+                }
+            }
+        } else if metadata.is_file() {
+            files.insert(path.into(), metadata.len());
+        } else {
+            let e = Error::FileTypeUnsupported(path.into(),
+                                               metadata.file_type());
+            keep_going::<()>(Err(e), options, errors)?;
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Split `files` into groups with identical contents, in stages: by size, then
+/// by a hash of the first and last blocks, with the cache enabled by a hash of
+/// the whole contents, and finally by comparing them fully.
+fn group_identical(files: Vec<(path::PathBuf, u64)>, options: &Options,
+                   errors: &mut Vec<Error>) -> Res<Vec<Vec<path::PathBuf>>>
+{
+    let mut by_len = BTreeMap::new();
+    for (path, len) in files {
+        by_len.entry(len).or_insert_with(Vec::new).push(path);
+    }
+
+    let mut groups = Vec::new();
+    for (len, paths) in by_len {
+        if paths.len() == 1 || len == 0 {
+            groups.push(paths);
+            continue;
+        }
+
+        let len_partial = cmp::min(len, options.buffer_size as u64);
+        let by_partial = group_by_hash(paths, Kind::Partial(len_partial),
+                                       options, errors, |path| {
+            hash_partial(path, len, len_partial)
+        })?;
+        for paths in by_partial {
+            if paths.len() == 1 {
+                groups.push(paths);
+                continue;
+            }
+            // with no cache, files are compared right away rather than read
+            // twice
+            let by_full = if options.cache.is_some() {
+                group_by_hash(paths, Kind::Full, options, errors, |path| {
+                    hash::hash_file_parallel(path, Algorithm::Blake3, options)
+                })?
+            } else {
+                vec![paths]
+            };
+            for paths in by_full {
+                if paths.len() == 1 {
+                    groups.push(paths);
+                } else {
+                    groups.extend(partition(paths, len, options, errors)?);
+                }
+            }
+        }
+    }
+    groups.sort();
+    Ok(groups)
+}
+
+/// Split `paths` into groups with the same hash of `kind`, computed with
+/// `hash` unless it is cached.
+fn group_by_hash<F>(paths: Vec<path::PathBuf>, kind: Kind, options: &Options,
+                    errors: &mut Vec<Error>, hash: F) ->
+    Res<Vec<Vec<path::PathBuf>>>
+    where F: Fn(&path::Path) -> Res<String>
+{
+    let mut by_hash = BTreeMap::new();
+    for path in paths {
+        let hash = cache::hash(&path, kind, options, || hash(&path));
+        let hash = match keep_going(hash, options, errors)? {
+            Some(hash) => hash,
+            None => continue,
+        };
+        by_hash.entry(hash).or_insert_with(Vec::new).push(path);
+    }
+    Ok(by_hash.into_values().collect())
+}
+
+/// Hash the first and last `len_partial` bytes of the file at `path`, of
+/// length `len`.
+fn hash_partial(path: &path::Path, len: u64, len_partial: u64) ->
+    Res<String>
+{
+    let mut file = fs::File::open(path).map_err(|e| (e, path))?;
+    let mut buf = vec![0; len_partial as usize];
+    let mut hasher = blake3::Hasher::new();
+
+    file.read_exact(&mut buf).map_err(|e| (e, path))?;
+    hasher.update(&buf);
+    if len > buf.len() as u64 {
+        file.seek(io::SeekFrom::Start(len - buf.len() as u64))
+            .map_err(|e| (e, path))?;
+        file.read_exact(&mut buf).map_err(|e| (e, path))?;
+        hasher.update(&buf);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Split `paths`, all of length `len`, into groups with identical contents by
+/// comparing each one to the first file of every group found so far.
+fn partition(paths: Vec<path::PathBuf>, len: u64, options: &Options,
+             errors: &mut Vec<Error>) -> Res<Vec<Vec<path::PathBuf>>>
+{
+    let mut groups: Vec<(Comparer, Vec<path::PathBuf>)> = Vec::new();
+    'paths: for path in paths {
+        for &mut (ref mut comparer, ref mut group) in groups.iter_mut() {
+            match keep_going(comparer.compare(&path), options, errors)? {
+                Some(Comparison::Same) | Some(Comparison::SameFile { .. }) => {
+                    group.push(path);
+                    continue 'paths;
+                },
+                Some(_) => (),
+                None => continue 'paths,
+            }
+        }
+        let comparer = Comparer::new(&path, len, options);
+        let comparer = match keep_going(comparer, options, errors)? {
+            Some(comparer) => comparer,
+            None => continue,
+        };
+        groups.push((comparer, vec![path]));
+    }
+    Ok(groups.into_iter().map(|(_, group)| group).collect())
+}
+
+pub struct Duplicates {
+    groups: Vec<Vec<path::PathBuf>>,
+}
+
+impl Duplicates {
+    pub fn groups(&self) -> &[Vec<path::PathBuf>] {
+        &self.groups
+    }
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.groups.is_empty() {
+            return write!(f, "no duplicate files found");
+        }
+        fmt_groups(&self.groups, f)
+    }
+}
+
+impl ToJson for Duplicates {
+    fn to_json(&self) -> Vec<Value> {
+        if self.groups.is_empty() {
+            return vec![json!({ "kind": "no_duplicates" })];
+        }
+        groups_to_json("duplicates", &self.groups)
+    }
+}
+
+impl From<Duplicates> for ExitStatus {
+    fn from(duplicates: Duplicates) -> Self {
+        if duplicates.groups.is_empty() {
+            ExitStatus::Different
+        } else {
+            ExitStatus::Same
+        }
+    }
+}
+
+pub struct Classes {
+    groups: Vec<Vec<path::PathBuf>>,
+}
+
+impl fmt::Display for Classes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_groups(&self.groups, f)
+    }
+}
+
+impl ToJson for Classes {
+    fn to_json(&self) -> Vec<Value> {
+        groups_to_json("class", &self.groups)
+    }
+}
+
+impl From<Classes> for ExitStatus {
+    fn from(classes: Classes) -> Self {
+        if classes.groups.len() == 1 {
+            ExitStatus::Same
+        } else {
+            ExitStatus::Different
+        }
+    }
+}
+
+fn fmt_groups(groups: &[Vec<path::PathBuf>], f: &mut fmt::Formatter) ->
+    fmt::Result
+{
+    for (i, group) in groups.iter().enumerate() {
+        if i > 0 {
+            write!(f, "\n\n")?;
+        }
+        for (j, path) in group.iter().enumerate() {
+            if j > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:?}", path)?;
+        }
+    }
+    Ok(())
+}
+
+fn groups_to_json(kind: &str, groups: &[Vec<path::PathBuf>]) -> Vec<Value> {
+    groups.iter()
+        .map(|group| json!({
+            "kind": kind,
+            "paths": group.iter()
+                .map(|path| path_to_json(path))
+                .collect::<Vec<_>>(),
+            "size": size_to_json(&group[0]),
+        }))
+        .collect()
+}
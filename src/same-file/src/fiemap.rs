@@ -0,0 +1,174 @@
+use std::os::unix::io::AsRawFd;
+use std::{cmp, fs, io};
+use libc;
+
+/// An extent of a file sharing its physical blocks with other files, e.g.
+/// through reflinks.
+#[derive(Clone, Copy)]
+pub struct SharedExtent {
+    logical: u64,
+    physical: u64,
+    len: u64,
+}
+
+/// Find the extents of `file` with shared physical blocks using the
+/// `FS_IOC_FIEMAP` ioctl; filesystems not supporting it have none.
+#[cfg(target_os = "linux")]
+pub fn shared_extents(file: &fs::File) -> io::Result<Vec<SharedExtent>> {
+    // from linux/fs.h and linux/fiemap.h
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+    const FIEMAP_EXTENT_LAST: u32 = 0x0001;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0002;
+    const FIEMAP_EXTENT_DELALLOC: u32 = 0x0004;
+    const FIEMAP_EXTENT_NOT_ALIGNED: u32 = 0x0100;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x0200;
+    const FIEMAP_EXTENT_DATA_TAIL: u32 = 0x0400;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    // extents whose physical location is unknown or not block-aligned
+    const FIEMAP_EXTENT_UNUSABLE: u32 = FIEMAP_EXTENT_UNKNOWN |
+        FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_NOT_ALIGNED |
+        FIEMAP_EXTENT_DATA_INLINE | FIEMAP_EXTENT_DATA_TAIL;
+    const EXTENT_COUNT: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fm_extents: [FiemapExtent; EXTENT_COUNT],
+    }
+
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut fiemap = Fiemap {
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            // without syncing the file first, as extents not yet allocated
+            // are left out anyway
+            fm_flags: 0,
+            fm_mapped_extents: 0,
+            fm_extent_count: EXTENT_COUNT as u32,
+            fm_reserved: 0,
+            fm_extents: [FiemapExtent::default(); EXTENT_COUNT],
+        };
+        let result = unsafe {
+            libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _,
+                        &mut fiemap as *mut Fiemap)
+        };
+        if result == -1 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) =>
+                    Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+
+        let mapped = &fiemap.fm_extents[..fiemap.fm_mapped_extents as usize];
+        for extent in mapped {
+            if extent.fe_flags & FIEMAP_EXTENT_SHARED != 0 &&
+                extent.fe_flags & FIEMAP_EXTENT_UNUSABLE == 0
+            {
+                extents.push(SharedExtent {
+                    logical: extent.fe_logical,
+                    physical: extent.fe_physical,
+                    len: extent.fe_length,
+                });
+            }
+        }
+        match mapped.last() {
+            Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 =>
+                start = last.fe_logical + last.fe_length,
+            _ => break,
+        }
+    }
+    Ok(extents)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn shared_extents(_file: &fs::File) -> io::Result<Vec<SharedExtent>> {
+    Ok(Vec::new())
+}
+
+/// Find the ranges at which two files on the same device share the same
+/// physical blocks, and so have the same contents.
+pub fn shared_ranges(left: &[SharedExtent], right: &[SharedExtent]) ->
+    Vec<(u64, u64)>
+{
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        let (l, r) = (left[i], right[j]);
+        let beg = cmp::max(l.logical, r.logical);
+        let end = cmp::min(l.logical + l.len, r.logical + r.len);
+        if beg < end &&
+            l.physical.wrapping_sub(l.logical) ==
+            r.physical.wrapping_sub(r.logical)
+        {
+            match ranges.last_mut() {
+                Some(last) if last.1 == beg => last.1 = end,
+                _ => ranges.push((beg, end)),
+            }
+        }
+        if l.logical + l.len < r.logical + r.len {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Extents of `(logical, physical, len)`.
+    fn extents(extents: &[(u64, u64, u64)]) -> Vec<SharedExtent> {
+        extents.iter()
+            .map(|&(logical, physical, len)| SharedExtent {
+                logical, physical, len,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shared_ranges_overlapping() {
+        let left = extents(&[(0, 100, 10)]);
+        assert_eq!(shared_ranges(&left, &extents(&[(0, 100, 10)])),
+                   [(0, 10)]);
+        assert_eq!(shared_ranges(&left, &extents(&[(5, 105, 10)])),
+                   [(5, 10)]);
+        assert_eq!(shared_ranges(&left, &extents(&[(2, 102, 3)])), [(2, 5)]);
+        // the same blocks at different offsets of each file
+        assert_eq!(shared_ranges(&left, &extents(&[(5, 100, 10)])), []);
+        assert_eq!(shared_ranges(&left, &extents(&[(10, 110, 10)])), []);
+        assert_eq!(shared_ranges(&left, &[]), []);
+    }
+
+    #[test]
+    fn shared_ranges_adjacent() {
+        let left = extents(&[(0, 100, 10), (10, 500, 10), (30, 130, 10)]);
+        let right = extents(&[(0, 100, 4), (4, 104, 6), (10, 500, 25)]);
+        assert_eq!(shared_ranges(&left, &right), [(0, 20)]);
+        assert_eq!(shared_ranges(&right, &left), [(0, 20)]);
+
+        let right = extents(&[(5, 105, 10), (15, 505, 20)]);
+        assert_eq!(shared_ranges(&left, &right), [(5, 10), (15, 20)]);
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt::Write;
+use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic;
+use std::{cmp, fs, io, path, thread};
+use blake3::{self, hazmat};
+use blake3::hazmat::HasherExt;
+use sha2::{self, Digest};
+use {cache, stream, Options, Res};
+
+/// The minimum number of bytes to hash per thread, for algorithms hashing
+/// parts of a file in parallel.
+const THREAD_LEN_MIN: u64 = 1 << 21;
+
+arg_enum!{
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Algorithm {
+        Sha256,
+        Sha512,
+        Blake3
+    }
+}
+
+impl Algorithm {
+    /// The name of the algorithm in tagged (BSD-style) checksum lines.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha512 => "SHA512",
+            Algorithm::Blake3 => "BLAKE3",
+        }
+    }
+
+    /// The number of hexadecimal digits in a hash.
+    pub fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::Blake3 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(buf),
+            Hasher::Sha512(hasher) => hasher.update(buf),
+            Hasher::Blake3(hasher) => {
+                hasher.update(buf);
+            },
+        }
+    }
+
+    /// Return the hash as lowercase hexadecimal digits.
+    fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Sha512(hasher) => to_hex(&hasher.finalize()),
+            Hasher::Blake3(hasher) => to_hex(hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+pub fn to_hex(hash: &[u8]) -> String {
+    let mut hex = String::with_capacity(hash.len() * 2);
+    for byte in hash {
+        write!(hex, "{:02x}", byte).expect("cannot write to string");
+    }
+    hex
+}
+
+/// Hash the contents of the file at `path`, or of the standard input for `-`,
+/// reading it into buffers of `buffer_size` bytes.
+pub fn hash_file(path: &path::Path, algorithm: Algorithm, buffer_size: usize) ->
+    Res<String>
+{
+    let mut reader: Box<dyn Read> = if stream::is_stdin(path) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(path).map_err(|e| (e, path))?)
+    };
+    let mut buf = vec![0; buffer_size];
+    let mut hasher = Hasher::new(algorithm);
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err((e, path).into()),
+        };
+        hasher.update(&buf[..len]);
+    }
+    Ok(hasher.finish())
+}
+
+/// Hash the contents of the file at `path` like `hash_file`, but for a large
+/// regular file hashed with BLAKE3, hash parts of its tree in parallel, with
+/// up to `options.threads_max` threads.
+pub fn hash_file_parallel(path: &path::Path, algorithm: Algorithm,
+                          options: &Options) -> Res<String>
+{
+    if stream::is_stdin(path) {
+        return hash_file(path, algorithm, options.buffer_size);
+    }
+    let file = fs::File::open(path).map_err(|e| (e, path))?;
+    let metadata = file.metadata().map_err(|e| (e, path))?;
+    let len = metadata.len();
+    let threads = cmp::min(options.threads_max as u64, len / THREAD_LEN_MIN);
+    if algorithm != Algorithm::Blake3 || !metadata.is_file() || threads <= 1 {
+        return hash_file(path, algorithm, options.buffer_size);
+    }
+    if options.verbose {
+        eprintln!("{:?}: hashing with {} threads", path, threads);
+    }
+
+    // the root node is the only one which is not finalized as a chaining
+    // value, so its two subtrees are hashed here
+    let left_len = hazmat::left_subtree_len(len);
+    let threads_left = (threads as usize).div_ceil(2);
+    let threads_right = threads as usize - threads_left;
+    let (left, right) = thread::scope(|scope| {
+        let left = scope.spawn(|| {
+            hash_subtree(&file, 0, left_len, threads_left, options)
+        });
+        let right = hash_subtree(&file, left_len, len - left_len,
+                                 threads_right, options);
+        (left.join().expect("worker thread panicked"), right)
+    });
+    let (left, right) = (left.map_err(|e| (e, path))?,
+                         right.map_err(|e| (e, path))?);
+    let hash = hazmat::merge_subtrees_root(&left, &right, hazmat::Mode::Hash);
+    Ok(to_hex(hash.as_bytes()))
+}
+
+/// Compute the BLAKE3 chaining value of the `len` bytes of `file` at
+/// `offset`, which must be a subtree of the whole file, with up to `threads`
+/// threads.
+fn hash_subtree(file: &fs::File, offset: u64, len: u64, threads: usize,
+                options: &Options) -> io::Result<hazmat::ChainingValue>
+{
+    if threads > 1 && len > blake3::CHUNK_LEN as u64 {
+        let left_len = hazmat::left_subtree_len(len);
+        let threads_left = threads.div_ceil(2);
+        let (left, right) = thread::scope(|scope| {
+            let left = scope.spawn(|| {
+                hash_subtree(file, offset, left_len, threads_left, options)
+            });
+            let right = hash_subtree(file, offset + left_len, len - left_len,
+                                     threads - threads_left, options);
+            (left.join().expect("worker thread panicked"), right)
+        });
+        return Ok(hazmat::merge_subtrees_non_root(&left?, &right?,
+                                                  hazmat::Mode::Hash));
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(offset);
+    let mut buf = vec![0; options.buffer_size];
+    let mut pos = 0;
+    while pos < len {
+        let buf_len = cmp::min(buf.len() as u64, len - pos) as usize;
+        // a file which got shorter since its size was read fails here
+        file.read_exact_at(&mut buf[..buf_len], offset + pos)?;
+        hasher.update(&buf[..buf_len]);
+        pos += buf_len as u64;
+    }
+    Ok(hasher.finalize_non_root())
+}
+
+/// Hash the contents of each file in `files` with its algorithm, with up to as
+/// many threads as the comparisons use, each hashing a file at a time; BLAKE3
+/// hashes are cached.
+pub fn hash_files(files: &[(path::PathBuf, Algorithm)], options: &Options) ->
+    Vec<Res<String>>
+{
+    let next = atomic::AtomicUsize::new(0);
+    let work = || {
+        let mut hashes = Vec::new();
+        loop {
+            let i = next.fetch_add(1, atomic::Ordering::Relaxed);
+            if i >= files.len() {
+                break hashes;
+            }
+            let (ref path, algorithm) = files[i];
+            let hash = || hash_file(path, algorithm, options.buffer_size);
+            let hash = match algorithm {
+                Algorithm::Blake3 =>
+                    cache::rehash(path, cache::Kind::Full, options, hash),
+                Algorithm::Sha256 | Algorithm::Sha512 => hash(),
+            };
+            hashes.push((i, hash));
+        }
+    };
+
+    let threads = cmp::min(options.threads_max, files.len());
+    let mut hashes = if threads <= 1 {
+        work()
+    } else {
+        thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|_| scope.spawn(work))
+                .collect::<Vec<_>>();
+            workers.into_iter()
+                .flat_map(|worker| {
+                    worker.join().expect("worker thread panicked")
+                })
+                .collect()
+        })
+    };
+    hashes.sort_by_key(|&(i, _)| i);
+    hashes.into_iter().map(|(_, hash)| hash).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn hash_file_parallel_blake3() {
+        let dir = env::temp_dir()
+            .join(format!("same-file-test-{}-hash", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        // around the length hashed per thread and the BLAKE3 chunk length
+        for &len in &[0, 1 << 22, (1 << 22) + 1, 10_000_000, (1 << 25) + 1,
+                      (1 << 25) + blake3::CHUNK_LEN as u64 - 1]
+        {
+            let mut state = len as u32;
+            let contents = (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245)
+                        .wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect::<Vec<_>>();
+            fs::write(&path, &contents).unwrap();
+            let expected = blake3::hash(&contents).to_hex().to_string();
+            for &threads_max in &[1, 3, 8] {
+                let options = Options {
+                    buffer_size: 100_000,
+                    threads_max,
+                    ..Options::default()
+                };
+                assert_eq!(hash_file_parallel(&path, Algorithm::Blake3,
+                                              &options).unwrap(),
+                           expected, "{} bytes, {} threads", len, threads_max);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
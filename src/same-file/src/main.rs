@@ -1,15 +1,50 @@
 #[macro_use]
 extern crate clap;
+extern crate flate2;
+extern crate blake3;
+extern crate libc;
+extern crate memmap2;
 extern crate num_cpus;
 #[macro_use]
 extern crate quick_error;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
 extern crate strum;
 #[macro_use]
 extern crate strum_macros;
+extern crate tar;
+extern crate unicode_normalization;
+extern crate xz2;
+extern crate zip;
+extern crate zstd;
+
+mod archive;
+mod cache;
+mod comparer;
+mod dedupe;
+mod diff;
+mod dump;
+mod duplicates;
+mod fiemap;
+mod hash;
+mod manifest;
+mod metadata;
+mod output;
+mod stream;
+mod text;
+mod tree;
 
-use std::{cmp, fmt, fs, io, path, process, sync, thread};
+use comparer::Comparer;
+use diff::Diff;
+use dump::Dump;
+use output::{path_to_json, size_to_json, Output, ToJson};
+use serde_json::Value;
+use std::{cmp, fmt, fs, io, path, process, sync};
 use std::fmt::Write as WriteFmt;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::os::unix::fs::MetadataExt;
 use strum::IntoEnumIterator;
 
 type Res<T> = Result<T, Error>;
@@ -41,6 +76,12 @@ fn run() -> Res<ExitStatus> {
     let args = clap::App::new(crate_name!())
         .about(crate_description!())
         .version(crate_version!())
+        // PATHs resembling a subcommand are not taken for a misspelled one,
+        // and PATHs named as one are taken for it only as the first argument
+        .setting(clap::AppSettings::AllowExternalSubcommands)
+        .setting(clap::AppSettings::ArgsNegateSubcommands)
+        .setting(clap::AppSettings::DisableHelpSubcommand)
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .after_help(format!(
             "Print whether the files are the same, and indicate it via the \
              exit status as well.\n\
@@ -48,7 +89,130 @@ fn run() -> Res<ExitStatus> {
              A regular file is considered the same as another one if they are \
              the same size and have the exact same contents.\n\
              \n\
-             Other file types are not supported.
+             With --recursive, each PATH must be a directory; files are \
+             matched by their paths relative to it, and files missing from \
+             either side, of a different type, or with different contents are \
+             printed.\n\
+             \n\
+             With --find-duplicates, each PATH may be a file or a directory; \
+             the groups of files with the same contents among all of them are \
+             printed, and the exit status indicates whether any were found.\n\
+             \n\
+             With --classes, all files are split into groups with the same \
+             contents, every group is printed, and the exit status indicates \
+             whether there was exactly one group.\n\
+             \n\
+             With --dedupe, once all files are found to have the same \
+             contents, or for each group found with --find-duplicates, every \
+             file but the first is replaced with a hard link or a reflink to \
+             the first one.  Each file is compared again right before it is \
+             replaced, and is replaced atomically.  Hard links are only made \
+             between files with the same permissions, owner and modification \
+             time; reflinks keep the permissions, owner and timestamps of the \
+             replaced file.  Of symbolic links, the targets are linked to and \
+             replaced.\n\
+             \n\
+             Symbolic links among the PATHs are followed by default.  With \
+             --symlinks=compare-target, a symbolic link is considered the \
+             same as another one if their targets are the same, and differs \
+             from a regular file; with --symlinks=error, it is an error.\n\
+             \n\
+             A PATH of - stands for the standard input.  It, named pipes and \
+             character devices are read as streams: all files are then read \
+             sequentially in a single pass, without threads or memory \
+             mapping, and a difference in size is only found once one of \
+             them ends.\n\
+             \n\
+             With --keep-going, files which cannot be read are reported and \
+             left out, and the others are still compared; the exit status \
+             only says that some comparisons could not be made if no \
+             difference, or with --find-duplicates no duplicates, were found \
+             among the others.\n\
+             \n\
+             With --expect-sha256, --expect-sha512 or --expect-blake3, each \
+             file is hashed instead and checked against the given digest; \
+             large files are hashed in parallel with BLAKE3.\n\
+             \n\
+             Hashes of the contents of files are cached across runs under \
+             $XDG_CACHE_HOME/same-file, keyed by the device and inode numbers \
+             of each file and kept as long as its size and modification time \
+             stay the same.  They are used to find files with different \
+             contents without reading them, and to pick the files to compare \
+             with --find-duplicates or --classes, but files are still \
+             compared fully before being found the same, and hashed before \
+             being found to have an expected digest.  --no-cache disables \
+             the cache.\n\
+             \n\
+             Before regular files are compared fully, their first and last \
+             blocks, and as many blocks as given with --samples at random \
+             offsets in between, are compared, which finds most differences \
+             quickly; only the contents before a difference found this way \
+             are then compared, for the first one.  With --sample-only, the \
+             files are not compared fully, and if no difference is found, the \
+             exit status says that they are probably the same.\n\
+             \n\
+             With --metadata, the given attributes of files found to have \
+             the same contents are compared as well: their permissions and \
+             special mode bits, their owner and group, their modification \
+             time, their extended attributes, or their POSIX ACLs.  With \
+             --recursive, this applies to every entry below the directories, \
+             including directories and symbolic links themselves.\n\
+             \n\
+             With --text, files are compared line by line, and the first \
+             line which differs is printed.  Differences in line endings (CRLF \
+             or LF, or none at the end of the file), in whitespace at the end \
+             of lines, in a byte order mark at the start of files, or in the \
+             Unicode normalization form (NFC or NFD) of lines can be ignored, \
+             and those which were are printed when files are the same.\n\
+             \n\
+             With --skip-left, --skip-right or --bytes, as with cmp -i and \
+             -n, bytes at the start of the first file, or of the others, are \
+             skipped, and at most as many bytes as given are compared after \
+             them; offsets are then counted from the start of each compared \
+             range, and ranges of different lengths differ.\n\
+             \n\
+             With --diff, files with different contents which both look \
+             like UTF-8 text are printed as a unified diff instead, with as \
+             many lines of context as given with --context-lines and up to \
+             as many hunks as given with --max-hunks; the exit status stays \
+             the same.  Files with thousands of lines changed are not \
+             diffed, as that would take too long.\n\
+             \n\
+             With --decompress, files compressed with gzip, xz or zstd, \
+             as told by the bytes they start with, are decompressed, and all \
+             files are then read as streams; offsets are those in the \
+             decompressed contents.\n\
+             \n\
+             With --show-diff, the bytes of both files around each \
+             difference in their contents are printed side by side in hex \
+             and ASCII, with the differing bytes highlighted on a \
+             terminal.\n\
+             \n\
+             With --archive, each PATH must be a tar file, which may be \
+             compressed with gzip, xz or zstd, or a zip file; their members \
+             are matched by their paths, regardless of their order, and \
+             those added, removed, or with a different type, size, contents \
+             or link target are printed.  Timestamps are ignored; with \
+             --metadata, the mode and owner of members can be compared as \
+             well.  Nothing is extracted.\n\
+             \n\
+             Other file types are not supported.\n\
+             \n\
+             The manifest subcommand creates or verifies checksums of the \
+             regular files under a directory, in the format of sha256sum or \
+             b3sum; bytes of paths which are not UTF-8 are escaped as \\xNN.  \
+             It is only recognized as the first argument, with any \
+             options after it; a first PATH named manifest is given as \
+             ./manifest instead.\n\
+             \n\
+             With --format=json, a single JSON object is printed, with the \
+             version of the format, the mode, and a list of results, each \
+             with its kind; errors are results of the kind \"error\".  A \
+             mode which finds nothing to report still gives a result, of the \
+             kind \"same\", or \"no_duplicates\" when finding duplicates.  \
+             With --format=ndjson, each result is printed as soon as it is \
+             found, as a JSON object on its own line with the version and the \
+             mode.\n\
              \n\
              Exit status:\n\
              {}",
@@ -62,11 +226,19 @@ fn run() -> Res<ExitStatus> {
             }).as_str())
         .arg(clap::Arg::with_name("PATH")
              .help("The files to compare.")
-             .min_values(2)
+             .min_values(1)
              .required(true))
+        .arg(clap::Arg::with_name("archive")
+             .long("archive")
+             .conflicts_with_all(&["classes", "decompress", "dedupe", "diff",
+                                   "expect", "find-duplicates", "line",
+                                   "list-differences", "recursive",
+                                   "sample-only", "show-diff", "text"])
+             .help("Compare the members of tar or zip archives."))
         .arg(clap::Arg::with_name("buffer-size")
              .short("b")
              .long("buffer-size")
+             .global(true)
              .help("The size of the buffers used for reading files.")
              .default_value("4096")
              .takes_value(true)
@@ -80,15 +252,237 @@ fn run() -> Res<ExitStatus> {
                  }
              })
              .value_name("BYTES"))
+        .arg(arg_range("bytes")
+             .help("The maximum number of bytes to compare.")
+             .value_name("COUNT"))
+        .arg(clap::Arg::with_name("classes")
+             .short("c")
+             .long("classes")
+             .conflicts_with_all(&["find-duplicates", "recursive"])
+             .help("\
+                 Split the files into groups with the same contents and print \
+                 every group."))
+        .arg(clap::Arg::with_name("context-bytes")
+             .long("context-bytes")
+             .help("\
+                 The number of bytes to dump before and after each \
+                 difference. [default: 16]")
+             .requires("show-diff")
+             .takes_value(true)
+             .validator(|context| {
+                 context.parse::<u64>()
+                     .map(|_| ())
+                     .map_err(|e| format!("{}", e))
+             })
+             .value_name("BYTES"))
+        .arg(clap::Arg::with_name("context-lines")
+             .long("context-lines")
+             .help("\
+                 The number of lines to print before and after each change. \
+                 [default: 3]")
+             .requires("diff")
+             .takes_value(true)
+             .validator(|context| {
+                 context.parse::<usize>()
+                     .map(|_| ())
+                     .map_err(|e| format!("{}", e))
+             })
+             .value_name("LINES"))
+        .arg(clap::Arg::with_name("decompress")
+             .long("decompress")
+             .conflicts_with_all(&["classes", "dedupe", "diff", "expect",
+                                   "find-duplicates", "line", "recursive",
+                                   "sample-only", "show-diff", "text"])
+             .help("\
+                 Decompress gzip, xz and zstd files before comparing them."))
+        .arg(clap::Arg::with_name("dedupe")
+             .long("dedupe")
+             .conflicts_with_all(&["classes", "list-differences",
+                                   "recursive"])
+             .help("\
+                 Replace files with the same contents as the first one with a \
+                 hard link or a reflink to it.")
+             .case_insensitive(true)
+             .possible_values(&dedupe::Method::variants())
+             .takes_value(true)
+             .value_name("METHOD"))
+        .arg(clap::Arg::with_name("diff")
+             .long("diff")
+             .conflicts_with_all(&["bytes", "classes", "expect",
+                                   "find-duplicates", "recursive",
+                                   "skip-left", "skip-right", "text"])
+             .help("\
+                 Print a unified diff of files which differ, if they look \
+                 like text."))
+        .arg(clap::Arg::with_name("dry-run")
+             .long("dry-run")
+             .requires("dedupe")
+             .help("Print how files would be deduplicated, without doing it."))
+        .arg(arg_expect("expect-blake3", hash::Algorithm::Blake3))
+        .arg(arg_expect("expect-sha256", hash::Algorithm::Sha256))
+        .arg(arg_expect("expect-sha512", hash::Algorithm::Sha512))
+        .group(clap::ArgGroup::with_name("expect")
+               .args(&["expect-blake3", "expect-sha256", "expect-sha512"]))
+        .arg(clap::Arg::with_name("find-duplicates")
+             .short("d")
+             .long("find-duplicates")
+             .conflicts_with("recursive")
+             .help("Find groups of files with the same contents."))
+        .arg(clap::Arg::with_name("format")
+             .long("format")
+             .global(true)
+             .help("The format of the output.")
+             .default_value("text")
+             .possible_values(&["text", "json", "ndjson"])
+             .takes_value(true)
+             .value_name("FORMAT"))
+        .arg(clap::Arg::with_name("ignore-bom")
+             .long("ignore-bom")
+             .requires("text")
+             .help("With --text, ignore a byte order mark at the start."))
+        .arg(clap::Arg::with_name("ignore-line-endings")
+             .long("ignore-line-endings")
+             .requires("text")
+             .help("With --text, ignore CRLF versus LF line endings."))
+        .arg(clap::Arg::with_name("ignore-normalization")
+             .long("ignore-normalization")
+             .requires("text")
+             .help("With --text, ignore NFC versus NFD normalization."))
+        .arg(clap::Arg::with_name("ignore-trailing-whitespace")
+             .long("ignore-trailing-whitespace")
+             .requires("text")
+             .help("With --text, ignore whitespace at the end of lines."))
+        .arg(clap::Arg::with_name("keep-going")
+             .short("k")
+             .long("keep-going")
+             .global(true)
+             .help("\
+                 Report files which cannot be read and go on comparing the \
+                 others."))
+        .arg(clap::Arg::with_name("line")
+             .short("l")
+             .long("line")
+             .conflicts_with_all(&["classes", "find-duplicates", "recursive"])
+             .help("\
+                 Also print the line and column of the first difference, if \
+                 the files look like text."))
+        .arg(clap::Arg::with_name("list-differences")
+             .long("list-differences")
+             .conflicts_with_all(&["classes", "find-duplicates", "line",
+                                   "recursive"])
+             .help("\
+                 Compare the files fully and print every range of bytes which \
+                 differs."))
+        .arg(clap::Arg::with_name("max-differences")
+             .long("max-differences")
+             .help("The maximum number of differing ranges to print.")
+             .requires("list-differences")
+             .takes_value(true)
+             .validator(|differences_max| {
+                 differences_max.parse::<usize>()
+                     .map(|_| ())
+                     .map_err(|e| format!("{}", e))
+             })
+             .value_name("COUNT"))
+        .arg(clap::Arg::with_name("max-hunks")
+             .long("max-hunks")
+             .help("The maximum number of hunks of the diff to print.")
+             .requires("diff")
+             .takes_value(true)
+             .validator(|hunks_max| {
+                 hunks_max.parse::<usize>()
+                     .map(|_| ())
+                     .map_err(|e| format!("{}", e))
+             })
+             .value_name("COUNT"))
+        .arg(clap::Arg::with_name("metadata")
+             .long("metadata")
+             .conflicts_with_all(&["classes", "expect", "find-duplicates"])
+             .help("\
+                 Also compare these attributes of the files, separated by \
+                 commas.")
+             .possible_values(&["mode", "owner", "mtime", "xattr", "acl"])
+             .takes_value(true)
+             .use_delimiter(true)
+             .value_name("ATTRIBUTES"))
+        .arg(clap::Arg::with_name("mmap")
+             .long("mmap")
+             .help("\
+                 Memory-map the files instead of reading them into buffers, \
+                 for those which can be mapped."))
+        .arg(clap::Arg::with_name("no-cache")
+             .long("no-cache")
+             .global(true)
+             .help("Neither use nor update the cache of hashes."))
         .arg(clap::Arg::with_name("quiet")
              .short("q")
              .long("quiet")
+             .global(true)
              .help("\
                  Print no output except error messages; the status code is the \
                  only output."))
+        .arg(clap::Arg::with_name("recursive")
+             .short("r")
+             .long("recursive")
+             .help("Compare directory trees recursively."))
+        .arg(clap::Arg::with_name("sample-only")
+             .long("sample-only")
+             .conflicts_with_all(&["classes", "dedupe", "expect",
+                                   "find-duplicates", "line",
+                                   "list-differences", "recursive"])
+             .help("\
+                 Only compare the sampled blocks of regular files, and report \
+                 whether they are probably the same."))
+        .arg(clap::Arg::with_name("samples")
+             .long("samples")
+             .help("\
+                 The number of blocks at random offsets to compare before \
+                 comparing files fully.")
+             .default_value("16")
+             .takes_value(true)
+             .validator(|samples| {
+                 samples.parse::<usize>()
+                     .map(|_| ())
+                     .map_err(|e| format!("{}", e))
+             })
+             .value_name("COUNT"))
+        .arg(clap::Arg::with_name("show-diff")
+             .long("show-diff")
+             .conflicts_with_all(&["classes", "expect", "find-duplicates",
+                                   "recursive", "text"])
+             .help("\
+                 Print a hex and ASCII dump of both files around the \
+                 differences found."))
+        .arg(arg_range("skip-left")
+             .conflicts_with("line")
+             .help("\
+                 The number of bytes to skip at the start of the first file.")
+             .value_name("BYTES"))
+        .arg(arg_range("skip-right")
+             .conflicts_with("line")
+             .help("\
+                 The number of bytes to skip at the start of the other files.")
+             .value_name("BYTES"))
+        .arg(clap::Arg::with_name("symlinks")
+             .long("symlinks")
+             .conflicts_with_all(&["classes", "find-duplicates", "recursive"])
+             .help("\
+                 How to handle symbolic links: follow them, compare their \
+                 targets, or fail. [default: follow]")
+             .possible_values(&["follow", "compare-target", "error"])
+             .takes_value(true)
+             .value_name("POLICY"))
+        .arg(clap::Arg::with_name("text")
+             .long("text")
+             .conflicts_with_all(&["classes", "dedupe", "expect",
+                                   "find-duplicates", "line",
+                                   "list-differences", "recursive",
+                                   "sample-only"])
+             .help("Compare the files as text, line by line."))
         .arg(clap::Arg::with_name("threads-max")
              .short("t")
              .long("threads-max")
+             .global(true)
              .help(&format!("\
                  Maximum number of threads to use. [default: {} (number of \
                  logical cores in the system)]", threads_max_default))
@@ -104,199 +498,1116 @@ fn run() -> Res<ExitStatus> {
                      Ok(())
                  }
              }))
+        .arg(clap::Arg::with_name("verbose")
+             .short("v")
+             .long("verbose")
+             .global(true)
+             .help("Print how each file is read to stderr."))
+        .subcommand(clap::SubCommand::with_name("manifest")
+             .about("Create or verify a manifest of checksums of files.")
+             .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(clap::SubCommand::with_name("create")
+                  .about("\
+                      Print the checksums of the regular files under a \
+                      directory, with their paths relative to it.")
+                  .arg(arg_algorithm())
+                  .arg(clap::Arg::with_name("tag")
+                       .long("tag")
+                       .help("\
+                           Print lines in the BSD style, naming the \
+                           algorithm."))
+                  .arg(clap::Arg::with_name("DIR")
+                       .help("The directory with the files.")
+                       .required(true)))
+             .subcommand(clap::SubCommand::with_name("verify")
+                  .about("\
+                      Check the regular files under a directory against a \
+                      manifest, and print those changed, missing, or new.")
+                  .arg(arg_algorithm())
+                  .arg(clap::Arg::with_name("MANIFEST")
+                       .help("The manifest to check against.")
+                       .required(true))
+                  .arg(clap::Arg::with_name("DIR")
+                       .help("The directory with the files.")
+                       .default_value("."))))
         .get_matches_safe()?;
 
-    let path_bufs = args.values_of("PATH").expect("no PATH")
+    let (mode, args) = match args.subcommand() {
+        ("manifest", Some(args)) => match args.subcommand() {
+            ("create", Some(args)) => ("manifest-create", args),
+            ("verify", Some(args)) => ("manifest-verify", args),
+            _ => unreachable!("no manifest subcommand"),
+        },
+        _ => {
+            let mode = if args.is_present("expect") {
+                "digest"
+            } else if args.is_present("find-duplicates") {
+                "find-duplicates"
+            } else if args.is_present("classes") {
+                "classes"
+            } else if args.is_present("recursive") {
+                "recursive"
+            } else if args.is_present("archive") {
+                "archive"
+            } else {
+                "compare"
+            };
+            (mode, &args)
+        },
+    };
+    let path_bufs = args.values_of("PATH").into_iter()
+        .flatten()
         .map(path::PathBuf::from)
         .collect::<Vec<_>>();
     let paths = path_bufs.iter()
         .map(|p| p.as_path())
         .collect::<Vec<_>>();
-    let buffer_size = value_t!(args.value_of("buffer-size"), usize)?;
-    let print_comparison = !args.is_present("quiet");
-    let threads_max = value_t!(args.value_of("threads-max"), usize)?;
+    let options = Options {
+        buffer_size: value_t!(args.value_of("buffer-size"), usize)?,
+        threads_max: value_t!(args.value_of("threads-max"), usize)?,
+        mmap: args.is_present("mmap"),
+        keep_going: args.is_present("keep-going"),
+        verbose: args.is_present("verbose"),
+        symlinks: match args.value_of("symlinks") {
+            Some(_) => value_t!(args.value_of("symlinks"), Symlinks)?,
+            None => Symlinks::Follow,
+        },
+        samples: match args.value_of("samples") {
+            Some(_) => Some(value_t!(args.value_of("samples"), usize)?),
+            None => None,
+        },
+        attributes: if args.is_present("metadata") {
+            values_t!(args.values_of("metadata"), metadata::Attribute)?
+        } else {
+            Vec::new()
+        },
+        cache: match cache::Cache::path_default() {
+            Some(ref path) if !args.is_present("no-cache") =>
+                Some(sync::Arc::new(cache::Cache::open(path))),
+            _ => None,
+        },
+        skip_left: match args.value_of("skip-left") {
+            Some(_) => value_t!(args.value_of("skip-left"), u64)?,
+            None => 0,
+        },
+        skip_right: match args.value_of("skip-right") {
+            Some(_) => value_t!(args.value_of("skip-right"), u64)?,
+            None => 0,
+        },
+        bytes: match args.value_of("bytes") {
+            Some(_) => Some(value_t!(args.value_of("bytes"), u64)?),
+            None => None,
+        },
+        decompress: args.is_present("decompress"),
+    };
+    if (mode == "compare" || mode == "recursive" || mode == "archive") &&
+        paths.len() < 2
+    {
+        return Err(clap::Error::with_description(
+            "at least 2 PATHs are required to compare",
+            clap::ErrorKind::TooFewValues).into());
+    }
+    let attributes_archive = [metadata::Attribute::Mode,
+                              metadata::Attribute::Owner];
+    if mode == "archive" &&
+        options.attributes.iter().any(|a| !attributes_archive.contains(a))
+    {
+        return Err(clap::Error::with_description(
+            "only the mode and owner of archive members can be compared",
+            clap::ErrorKind::InvalidValue).into());
+    }
+
+    let format = value_t!(args.value_of("format"), output::Format)?;
+    let mut output = Output::new(format, args.is_present("quiet"), mode);
+    let result = run_mode(mode, args, paths.as_slice(), &options, &mut output);
+    if let Some(ref cache) = options.cache {
+        // the results hold regardless
+        if let Err(e) = cache.save() {
+            eprintln!("cannot save the cache: {}", e);
+        }
+    }
+    output.finish(result)
+}
+
+/// An argument limiting the range of bytes compared, which only applies to
+/// comparing files directly.
+fn arg_range(name: &'static str) -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name(name)
+        .long(name)
+        .conflicts_with_all(&["archive", "classes", "dedupe", "expect",
+                              "find-duplicates", "recursive", "text"])
+        .takes_value(true)
+        .validator(|count| {
+            count.parse::<u64>()
+                .map(|_| ())
+                .map_err(|e| format!("{}", e))
+        })
+}
+
+fn arg_algorithm<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("algorithm")
+        .short("a")
+        .long("algorithm")
+        .help("\
+            The hash algorithm, for the lines not naming theirs when \
+            verifying.")
+        .case_insensitive(true)
+        .default_value("sha256")
+        .possible_values(&hash::Algorithm::variants())
+        .takes_value(true)
+}
+
+fn arg_expect(name: &'static str, algorithm: hash::Algorithm) ->
+    clap::Arg<'static, 'static>
+{
+    clap::Arg::with_name(name)
+        .long(name)
+        .conflicts_with_all(&["classes", "dedupe", "find-duplicates", "line",
+                              "list-differences", "recursive", "symlinks"])
+        .help(match algorithm {
+            hash::Algorithm::Sha256 =>
+                "Check that each file has the given SHA-256 digest.",
+            hash::Algorithm::Sha512 =>
+                "Check that each file has the given SHA-512 digest.",
+            hash::Algorithm::Blake3 =>
+                "Check that each file has the given BLAKE3 digest.",
+        })
+        .takes_value(true)
+        .validator(move |digest| {
+            if digest.len() == algorithm.hex_len() &&
+                digest.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                Ok(())
+            } else {
+                Err(format!("must be {} hexadecimal digits",
+                            algorithm.hex_len()))
+            }
+        })
+        .value_name("HEX")
+}
+
+fn run_mode(mode: &str, args: &clap::ArgMatches, paths: &[&path::Path],
+            options: &Options, output: &mut Output) -> Res<ExitStatus>
+{
+    let dedupe = if args.is_present("dedupe") {
+        Some(value_t!(args.value_of("dedupe"), dedupe::Method)?)
+    } else {
+        None
+    };
+    let dry_run = args.is_present("dry-run");
+    let mut errors = Vec::new();
+
+    if mode == "manifest-create" {
+        let dir = path::Path::new(args.value_of_os("DIR").expect("no DIR"));
+        let algorithm = value_t!(args.value_of("algorithm"), hash::Algorithm)?;
+        let manifest = manifest::create(dir, algorithm, args.is_present("tag"),
+                                        options, &mut errors)?;
+        output.errors(&mut errors);
+        output.print(&manifest);
+        return Ok(incomplete(manifest.into(), output));
+    }
+
+    if mode == "manifest-verify" {
+        let manifest = path::Path::new(args.value_of_os("MANIFEST")
+                                       .expect("no MANIFEST"));
+        let dir = path::Path::new(args.value_of_os("DIR").expect("no DIR"));
+        let algorithm = value_t!(args.value_of("algorithm"), hash::Algorithm)?;
+        let verification = manifest::verify(manifest, dir, algorithm, options,
+                                            &mut errors)?;
+        output.errors(&mut errors);
+        output.print(&verification);
+        return Ok(incomplete(verification.into(), output));
+    }
+
+    if mode == "digest" {
+        let (name, algorithm) = [
+            ("expect-blake3", hash::Algorithm::Blake3),
+            ("expect-sha256", hash::Algorithm::Sha256),
+            ("expect-sha512", hash::Algorithm::Sha512),
+        ].iter()
+            .cloned()
+            .find(|&(name, _)| args.is_present(name))
+            .expect("no expected digest");
+        let expected = args.value_of(name).expect("no expected digest")
+            .to_ascii_lowercase();
+        let paths = readable(paths, options, &mut errors)?;
+        output.errors(&mut errors);
+        if paths.is_empty() {
+            return Ok(ExitStatus::Incomplete);
+        }
+        let comparison = compare_digests(&paths, algorithm, &expected,
+                                         options)?;
+        output.print(&Compared {
+            paths: &paths,
+            comparison: &comparison,
+            digest: Some((algorithm, &expected)),
+        });
+        return Ok(incomplete(comparison.into(), output));
+    }
+
+    if args.is_present("find-duplicates") {
+        let duplicates = duplicates::find_duplicates(paths, options,
+                                                     &mut errors)?;
+        output.errors(&mut errors);
+        output.print(&duplicates);
+        if let Some(method) = dedupe {
+            for group in duplicates.groups() {
+                let paths = group.iter()
+                    .map(|p| p.as_path())
+                    .collect::<Vec<_>>();
+                let deduplications = dedupe::dedupe(&paths, method, dry_run,
+                                                    options, &mut errors)?;
+                output.errors(&mut errors);
+                for deduplication in deduplications {
+                    output.print(&deduplication);
+                }
+            }
+        }
+        // duplicates found among the files which could be read are still
+        // duplicates
+        return Ok(match duplicates.into() {
+            ExitStatus::Different if output.incomplete() =>
+                ExitStatus::Incomplete,
+            status => status,
+        });
+    }
+
+    if args.is_present("classes") {
+        let classes = duplicates::find_classes(paths, options, &mut errors)?;
+        output.errors(&mut errors);
+        output.print(&classes);
+        return Ok(incomplete(classes.into(), output));
+    }
 
-    let comparison = compare_all(paths.as_slice(), buffer_size, threads_max)?;
-    if print_comparison {
-        println!("{}", comparison);
+    if args.is_present("recursive") {
+        let comparison = tree::compare_trees(paths, options, &mut errors)?;
+        output.errors(&mut errors);
+        output.print(&comparison);
+        return Ok(incomplete(comparison.into(), output));
+    }
+
+    if args.is_present("archive") {
+        let comparison = archive::compare_archives(paths, options,
+                                                   &mut errors)?;
+        output.errors(&mut errors);
+        output.print(&comparison);
+        return Ok(incomplete(comparison.into(), output));
+    }
+
+    let differences_max = if args.is_present("list-differences") {
+        Some(match args.value_of("max-differences") {
+            Some(_) => value_t!(args.value_of("max-differences"), usize)?,
+            None => usize::MAX,
+        })
+    } else {
+        None
+    };
+    let paths = readable(paths, options, &mut errors)?;
+    output.errors(&mut errors);
+    if paths.len() < 2 {
+        return Ok(ExitStatus::Incomplete);
+    }
+    let sample_only = args.is_present("sample-only");
+    let comparison = if args.is_present("text") {
+        let rules = [
+            ("ignore-line-endings", text::Rule::LineEndings),
+            ("ignore-trailing-whitespace", text::Rule::TrailingWhitespace),
+            ("ignore-bom", text::Rule::Bom),
+            ("ignore-normalization", text::Rule::Normalization),
+        ].iter()
+            .filter(|&&(name, _)| args.is_present(name))
+            .map(|&(_, rule)| rule)
+            .collect::<Vec<_>>();
+        text::compare_text(&paths, &rules, options)?
+    } else if args.is_present("line") {
+        // neither the cache nor the samples tell where files first differ
+        let options = Options { cache: None, samples: None, ..options.clone() };
+        compare_all(&paths, &options, differences_max, false)?
+            .locate_line(options.buffer_size)?
+    } else {
+        compare_all(&paths, options, differences_max, sample_only)?
+    };
+    let comparison = match comparison {
+        Comparison::Same | Comparison::SameFile { .. } |
+        Comparison::ProbablySame | Comparison::SameText { .. } =>
+            match compare_metadata(&paths, options)? {
+                Some(comparison) => comparison,
+                None => comparison,
+            },
+        comparison => comparison,
+    };
+    let diff = if args.is_present("diff") {
+        let context = match args.value_of("context-lines") {
+            Some(_) => value_t!(args.value_of("context-lines"), usize)?,
+            None => 3,
+        };
+        let hunks_max = match args.value_of("max-hunks") {
+            Some(_) => value_t!(args.value_of("max-hunks"), usize)?,
+            None => usize::MAX,
+        };
+        Diff::new(&comparison, context, hunks_max, options)?
+    } else {
+        None
+    };
+    match diff {
+        Some(diff) => output.print(&diff),
+        None => output.print(&Compared {
+            paths: &paths,
+            comparison: &comparison,
+            digest: None,
+        }),
+    }
+    if args.is_present("show-diff") {
+        let context = match args.value_of("context-bytes") {
+            Some(_) => value_t!(args.value_of("context-bytes"), u64)?,
+            None => 16,
+        };
+        let highlight = io::stdout().is_terminal();
+        if let Some(dump) = Dump::new(&comparison, context, highlight,
+                                      options)?
+        {
+            output.print(&dump);
+        }
+    }
+    if let (Comparison::Same, Some(method)) |
+        (Comparison::SameFile { .. }, Some(method)) = (&comparison, dedupe)
+    {
+        let deduplications = dedupe::dedupe(&paths, method, dry_run, options,
+                                            &mut errors)?;
+        output.errors(&mut errors);
+        for deduplication in deduplications {
+            output.print(&deduplication);
+        }
+    }
+    Ok(incomplete(comparison.into(), output))
+}
+
+/// Turn a status saying that the files are, or probably are, the same into one
+/// saying that some of them could not be compared, if any errors were skipped
+/// over.
+fn incomplete(status: ExitStatus, output: &Output) -> ExitStatus {
+    match status {
+        ExitStatus::Same | ExitStatus::ProbablySame if output.incomplete() =>
+            ExitStatus::Incomplete,
+        status => status,
+    }
+}
+
+/// Return the value of `result`, or with --keep-going, none if it is an error
+/// about a single file, which is moved to `errors` to go on with other files.
+fn keep_going<T>(result: Res<T>, options: &Options, errors: &mut Vec<Error>) ->
+    Res<Option<T>>
+{
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => if options.keep_going && e.path().is_some() {
+            errors.push(e);
+            Ok(None)
+        } else {
+            Err(e)
+        },
+    }
+}
+
+/// Leave out the paths which cannot be compared with --keep-going, moving
+/// their errors to `errors`.
+fn readable<'a>(paths: &[&'a path::Path], options: &Options,
+                errors: &mut Vec<Error>) -> Res<Vec<&'a path::Path>>
+{
+    let mut readable = Vec::with_capacity(paths.len());
+    for &path in paths {
+        if !options.keep_going || stream::is_stdin(path) {
+            readable.push(path);
+            continue;
+        }
+        let result = metadata_file(path, options.symlinks)
+            .and_then(|metadata| {
+                // opening a stream might block, or lose data
+                if metadata.is_file() {
+                    fs::File::open(path).map_err(|e| (e, path))?;
+                }
+                Ok(())
+            });
+        if keep_going(result, options, errors)?.is_some() {
+            readable.push(path);
+        }
     }
-    Ok(comparison.into())
+    Ok(readable)
 }
 
-fn compare_all(paths: &[&path::Path], buffer_size: usize, threads_max: usize) ->
+/// Compare the first of `paths` to each of the others, listing up to
+/// `differences_max` differences if given, or, with `sample_only`, only
+/// comparing samples of regular files.
+fn compare_all(paths: &[&path::Path], options: &Options,
+               differences_max: Option<usize>, sample_only: bool) ->
     Res<Comparison>
 {
+    let mut metadata_all = Vec::with_capacity(paths.len());
+    for &path in paths {
+        if stream::is_stdin(path) {
+            return stream::compare_streams(paths, options, differences_max);
+        }
+        let metadata = metadata_file(path, options.symlinks)?;
+        if stream::is_stream(&metadata.file_type()) || options.decompress {
+            return stream::compare_streams(paths, options, differences_max);
+        }
+        metadata_all.push(metadata);
+    }
+
     let (first, rest) = paths.split_at(1);
     let first = *first.first().expect("no first element in first");
-    let metadata_first = fs::metadata(first).map_err(|e| (e, first))?;
-    if !metadata_first.file_type().is_file() {
-        return Err(Error::FileTypeUnsupported(
-            first.into(), metadata_first.file_type()).into())
-    }
+    let (metadata_first, metadata_rest) = metadata_all.split_at(1);
+    let metadata_first = metadata_first.first()
+        .expect("no first element in metadata_first");
+    let type_first = metadata_first.file_type();
+    let len_first = range_len(metadata_first.len(), options.skip_left,
+                              options);
 
-    for &path in rest {
-        let metadata = fs::metadata(path).map_err(|e| (e, path))?;
-        if !metadata.file_type().is_file() {
-            return Err(Error::FileTypeUnsupported(
-                path.into(), metadata.file_type()).into());
+    for (&path, metadata) in rest.iter().zip(metadata_rest) {
+        if metadata.file_type() != type_first {
+            return Ok(Comparison::DifferentType {
+                left: first.into(), type_left: type_first,
+                right: path.into(), type_right: metadata.file_type(),
+            });
         }
-        if metadata.len() != metadata_first.len() {
+        if type_first.is_symlink() {
+            match compare_symlinks(first, path)? {
+                Comparison::Same => (),
+                comparison => return Ok(comparison),
+            }
+        } else if options.ranged() {
+            let len = range_len(metadata.len(), options.skip_right, options);
+            if len != len_first {
+                return Ok(Comparison::DifferentRange {
+                    left: first.into(), len_left: len_first,
+                    right: path.into(), len_right: len,
+                });
+            }
+        } else if metadata.len() != metadata_first.len() {
             return Ok(Comparison::DifferentSize {
                 left: first.into(), len_left: metadata_first.len(),
                 right: path.into(), len_right: metadata.len(),
             });
         }
     }
+    if type_first.is_symlink() {
+        return Ok(Comparison::Same);
+    }
+
+    // the cached hashes are of the whole contents
+    if differences_max.is_none() && !options.ranged() {
+        for &path in rest {
+            if cache::differ(first, path, options) {
+                return Ok(Comparison::DifferentHash {
+                    left: first.into(), right: path.into(),
+                });
+            }
+        }
+    }
 
-    let mut comparer = Comparer::new(&first, metadata_first.len(),
-                                     threads_max, buffer_size)?;
-    for path in rest {
-        match comparer.compare(&path)? {
-            Comparison::Same => (),
+    let mut comparer = Comparer::new(&first, len_first, options)?;
+    let mut sampled = false;
+    // whether every file is the first one, which is then reported
+    let mut same_file = true;
+    for &path in rest {
+        let comparison = match differences_max {
+            Some(differences_max) =>
+                comparer.list_differences(path, differences_max)?,
+            None if sample_only => {
+                let (comparison, all) = comparer.sample(path)?;
+                sampled |= !all;
+                comparison
+            },
+            None => comparer.compare(path)?,
+        };
+        match comparison {
+            Comparison::Same => same_file = false,
+            Comparison::SameFile { .. } => (),
             comparison => return Ok(comparison),
         };
     }
-    Ok(Comparison::Same)
+    Ok(if sampled {
+        Comparison::ProbablySame
+    } else if same_file {
+        Comparison::SameFile {
+            device: metadata_first.dev(), inode: metadata_first.ino(),
+        }
+    } else {
+        Comparison::Same
+    })
 }
 
-#[derive(Clone)]
-struct Comparer {
-    to_path: path::PathBuf,
-    to_len: u64,
-    threads: usize,
-    buffer_size: usize,
+/// The length of the range compared in a file of length `len`, after `skip`
+/// bytes and up to the number of bytes of `options`.
+fn range_len(len: u64, skip: u64, options: &Options) -> u64 {
+    let len = len.saturating_sub(skip);
+    options.bytes.map_or(len, |bytes| cmp::min(len, bytes))
 }
 
-impl Comparer {
-    fn new(to: &path::Path, to_len: u64, threads_max: usize,
-           buffer_size: usize) -> Res<Self> {
-        let blocks = to_len as usize / buffer_size;
-        const THREAD_BLOCKS_MIN: usize = 500;
-        let threads = blocks / THREAD_BLOCKS_MIN;
-        let threads = cmp::max(cmp::min(threads, 1), threads_max);
-        Ok(Self {
-            to_path: to.into(),
-            to_len,
-            threads,
-            buffer_size,
-        })
+/// Hash each of `paths` with `algorithm` and compare it to the `expected` hash,
+/// until one of them differs.
+fn compare_digests(paths: &[&path::Path], algorithm: hash::Algorithm,
+                   expected: &str, options: &Options) -> Res<Comparison>
+{
+    for &path in paths {
+        if !stream::is_stdin(path) {
+            metadata_file(path, options.symlinks)?;
+        }
+        let hash = || hash::hash_file_parallel(path, algorithm, options);
+        let actual = match algorithm {
+            hash::Algorithm::Blake3 =>
+                cache::rehash(path, cache::Kind::Full, options, hash)?,
+            hash::Algorithm::Sha256 | hash::Algorithm::Sha512 => hash()?,
+        };
+        if actual != expected {
+            return Ok(Comparison::DifferentDigest {
+                path: path.into(), algorithm,
+                expected: expected.into(), actual,
+            });
+        }
     }
+    Ok(Comparison::Same)
+}
 
-    fn compare(&mut self, file_path: &path::Path) -> Res<Comparison> {
-        if self.threads == 1 {
-            // 1 thread: do it on the current thread; do not spawn any new ones
-            return self.compare_segment(file_path, 0, self.to_len);
-        }
-
-        // multiple threads: spawn each one and wait for the first non-Same
-        // comparison; if all comparisons are Same, the enitre compare is Same
-        let blocks = self.to_len as usize / self.buffer_size;
-        let blocks_leftover = self.to_len as usize % self.buffer_size;
-        let blocks_thread = blocks / self.threads;
-        let threads_large = blocks % self.threads;
-
-        let (comparison_send, comparison_recv) = sync::mpsc::channel();
-        for i in 0..self.threads {
-            let comparison_send = comparison_send.clone();
-            let file_path: path::PathBuf = file_path.into();
-            let comparer = self.clone();
-            thread::spawn(move || {
-                let beg_blocks = i * blocks_thread + cmp::min(i, threads_large);
-                let beg = (beg_blocks * comparer.buffer_size) as u64;
-                let len_blocks = blocks_thread +
-                    if i < threads_large { 1 } else { 0 };
-                let len = len_blocks * comparer.buffer_size +
-                    if i < comparer.threads - 1 { 0 } else { blocks_leftover };
-                let end = beg + len as u64;
-                comparison_send.send(
-                    comparer.compare_segment(&file_path, beg, end))
-            });
+/// Compare the attributes given with --metadata of the first of `paths` to
+/// those of each of the others, but the standard input.
+fn compare_metadata(paths: &[&path::Path], options: &Options) ->
+    Res<Option<Comparison>>
+{
+    let follow = match options.symlinks {
+        Symlinks::Follow => true,
+        Symlinks::CompareTarget | Symlinks::Error => false,
+    };
+    let (first, rest) = paths.split_at(1);
+    let first = *first.first().expect("no first element in first");
+    if stream::is_stdin(first) {
+        return Ok(None);
+    }
+    for &path in rest {
+        if stream::is_stdin(path) {
+            continue;
         }
+        let comparison = metadata::compare(first, path, &options.attributes,
+                                           follow)?;
+        if comparison.is_some() {
+            return Ok(comparison);
+        }
+    }
+    Ok(None)
+}
 
-        for _ in 0..self.threads {
-            match comparison_recv.recv().unwrap()? {
-                Comparison::Same => (),
-                comparison => return Ok(comparison),
-            }
+/// Read the metadata of a regular file or a stream, or of a symbolic link
+/// itself if symbolic links are compared by their targets.
+fn metadata_file(path: &path::Path, symlinks: Symlinks) ->
+    Res<fs::Metadata>
+{
+    let metadata = match symlinks {
+        Symlinks::Follow => fs::metadata(path),
+        Symlinks::CompareTarget | Symlinks::Error => fs::symlink_metadata(path),
+    }.map_err(|e| (e, path))?;
+    let file_type = metadata.file_type();
+    let supported = stream::is_stream(&file_type) || match symlinks {
+        Symlinks::CompareTarget =>
+            file_type.is_file() || file_type.is_symlink(),
+        Symlinks::Follow | Symlinks::Error => file_type.is_file(),
+    };
+    if !supported {
+        return Err(Error::FileTypeUnsupported(path.into(), file_type));
+    }
+    Ok(metadata)
+}
+
+fn compare_files(left: &path::Path, len_left: u64,
+                 right: &path::Path, len_right: u64,
+                 options: &Options) -> Res<Comparison>
+{
+    if len_left != len_right {
+        return Ok(Comparison::DifferentSize {
+            left: left.into(), len_left,
+            right: right.into(), len_right,
+        });
+    }
+    if cache::differ(left, right, options) {
+        return Ok(Comparison::DifferentHash {
+            left: left.into(), right: right.into(),
+        });
+    }
+    Comparer::new(left, len_left, options)?.compare(right)
+}
+
+fn compare_symlinks(left: &path::Path, right: &path::Path) ->
+    Res<Comparison>
+{
+    let target_left = fs::read_link(left).map_err(|e| (e, left))?;
+    let target_right = fs::read_link(right).map_err(|e| (e, right))?;
+    Ok(if target_left == target_right {
+        Comparison::Same
+    } else {
+        Comparison::DifferentTarget {
+            left: left.into(), target_left,
+            right: right.into(), target_right,
         }
-        Ok(Comparison::Same)
+    })
+}
+
+#[derive(Clone)]
+struct Options {
+    buffer_size: usize,
+    threads_max: usize,
+    mmap: bool,
+    keep_going: bool,
+    verbose: bool,
+    symlinks: Symlinks,
+    /// The number of blocks to sample between the first and last ones before
+    /// comparing files fully, if they are sampled.
+    samples: Option<usize>,
+    /// The attributes to compare besides the contents.
+    attributes: Vec<metadata::Attribute>,
+    cache: Option<sync::Arc<cache::Cache>>,
+    /// The number of bytes to skip at the start of the first file, and of
+    /// the others.
+    skip_left: u64,
+    skip_right: u64,
+    /// The number of bytes to compare at most, after those skipped.
+    bytes: Option<u64>,
+    decompress: bool,
+}
+
+impl Options {
+    /// Whether only a range of the contents of files is compared.
+    fn ranged(&self) -> bool {
+        self.skip_left > 0 || self.skip_right > 0 || self.bytes.is_some()
     }
+}
 
-    fn compare_segment(&self, file_path: &path::Path, beg: u64, end: u64) ->
-        Res<Comparison>
-    {
-        let to = fs::File::open(&self.to_path).map_err(|e| (e, &self.to_path))?;
-        let mut to = io::BufReader::with_capacity(self.buffer_size, to);
-        let file = fs::File::open(file_path).map_err(|e| (e, file_path))?;
-        let mut file = io::BufReader::with_capacity(self.buffer_size, file);
-
-        let beg_pos = io::SeekFrom::Start(beg);
-        to.seek(beg_pos)
-            .expect(&format!("cannot seek to {:?}: {:?}",
-                             beg_pos, self.to_path));
-        file.seek(beg_pos)
-            .expect(&format!("cannot seek to {:?}: {:?}", beg_pos, file_path));
-
-        let mut pos = beg;
-        loop {
-            if pos >= end {
-                // checked up to or past position `to`
-                break Ok(Comparison::Same);
-            }
-            let len = {
-                //let to_path = self.to_path.clone();
-                let buf_to = to.fill_buf().map_err(|e| (e, &self.to_path))?;
-                let buf_file = file.fill_buf().map_err(|e| (e, file_path))?;
-                if buf_to.is_empty() && buf_file.is_empty() {
-                    // EOF at same position
-                    break Ok(Comparison::Same);
-                }
-                if buf_to.len() != buf_file.len() {
-                    // EOF at different positions
-                    return Ok(Comparison::DifferentSize {
-                        left: self.to_path.clone(),
-                        len_left: self.to_path.metadata()
-                            .map_err(|e| (e, &self.to_path))?
-                            .len(),
-                        right: file_path.into(),
-                        len_right: file_path.metadata()
-                            .map_err(|e| (e, file_path))?
-                            .len(),
-                    });
-                }
-                if buf_to != buf_file {
-                    return Ok(Comparison::DifferentContents {
-                        left: self.to_path.clone(),
-                        right: file_path.into(),
-                    });
-                }
-                buf_to.len()
-            };
-            to.consume(len);
-            file.consume(len);
-            pos += len as u64;
+#[cfg(test)]
+impl Default for Options {
+    /// The options as by default on the command line, but with one thread and
+    /// no cache.
+    fn default() -> Self {
+        Options {
+            buffer_size: 4096,
+            threads_max: 1,
+            mmap: false,
+            keep_going: false,
+            verbose: false,
+            symlinks: Symlinks::Follow,
+            samples: None,
+            attributes: Vec::new(),
+            cache: None,
+            skip_left: 0,
+            skip_right: 0,
+            bytes: None,
+            decompress: false,
         }
     }
 }
 
+#[derive(Clone, Copy, EnumString)]
+enum Symlinks {
+    #[strum(serialize = "follow")]
+    Follow,
+    #[strum(serialize = "compare-target")]
+    CompareTarget,
+    #[strum(serialize = "error")]
+    Error,
+}
+
 enum Comparison {
     Same,
+    /// The same file given more than once, e.g. through hard links, with its
+    /// device and inode numbers.
+    SameFile { device: u64, inode: u64 },
+    ProbablySame,
+    SameText { rules: Vec<text::Rule> },
     DifferentSize { left: path::PathBuf, len_left: u64,
                     right: path::PathBuf, len_right: u64 },
-    DifferentContents { left: path::PathBuf, right: path::PathBuf },
+    DifferentRange { left: path::PathBuf, len_left: u64,
+                     right: path::PathBuf, len_right: u64 },
+    DifferentContents { left: path::PathBuf, right: path::PathBuf,
+                        offset: u64, byte_left: u8, byte_right: u8,
+                        line_column: Option<(u64, u64)> },
+    DifferentExtents { left: path::PathBuf, right: path::PathBuf,
+                       extents: Vec<Extent>, truncated: bool },
+    DifferentHash { left: path::PathBuf, right: path::PathBuf },
+    DifferentSample { left: path::PathBuf, right: path::PathBuf,
+                      offset: u64, byte_left: u8, byte_right: u8 },
+    DifferentLength { left: path::PathBuf, right: path::PathBuf,
+                      len: u64, left_ended: bool },
+    DifferentType { left: path::PathBuf, type_left: fs::FileType,
+                    right: path::PathBuf, type_right: fs::FileType },
+    DifferentTarget { left: path::PathBuf, target_left: path::PathBuf,
+                      right: path::PathBuf, target_right: path::PathBuf },
+    DifferentDigest { path: path::PathBuf, algorithm: hash::Algorithm,
+                      expected: String, actual: String },
+    DifferentMetadata { left: path::PathBuf, right: path::PathBuf,
+                        differences: Vec<metadata::Difference> },
+    DifferentLine { left: path::PathBuf, right: path::PathBuf, line: u64,
+                    text_left: Option<String>, text_right: Option<String> },
+}
+
+struct Extent {
+    offset: u64,
+    len: u64,
+}
+
+impl Comparison {
+    /// Find the line and column of the first difference, if the contents
+    /// before it look like text.
+    fn locate_line(self, buffer_size: usize) -> Res<Self> {
+        let (left, right, offset, byte_left, byte_right) = match self {
+            Comparison::DifferentContents {
+                left, right, offset, byte_left, byte_right, ..
+            } => (left, right, offset, byte_left, byte_right),
+            comparison => return Ok(comparison),
+        };
+
+        // the contents before the difference are the same in both files, so
+        // they can be read again from either one which is not a stream
+        let path = if !stream::is_stream_path(&left) {
+            left.clone()
+        } else if !stream::is_stream_path(&right) {
+            right.clone()
+        } else {
+            return Ok(Comparison::DifferentContents {
+                left, right, offset, byte_left, byte_right, line_column: None,
+            });
+        };
+        let file = fs::File::open(&path).map_err(|e| (e, &path))?;
+        let mut file = io::BufReader::with_capacity(buffer_size, file)
+            .take(offset);
+        let mut buf = vec![0; buffer_size];
+        let mut line = 1;
+        let mut column = 1;
+        let mut text = true;
+        loop {
+            let len = file.read(&mut buf).map_err(|e| (e, &path))?;
+            if len == 0 {
+                break;
+            }
+            if buf[..len].contains(&0) {
+                // NUL bytes do not occur in text files
+                text = false;
+                break;
+            }
+            for &byte in &buf[..len] {
+                if byte == b'\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+            }
+        }
+
+        Ok(Comparison::DifferentContents {
+            left, right, offset, byte_left, byte_right,
+            line_column: if text { Some((line, column)) } else { None },
+        })
+    }
 }
 
 impl fmt::Display for Comparison {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Comparison::Same => write!(f, "files are the same"),
+            Comparison::SameFile { device, inode } =>
+                write!(f, "files are the same: the same file (device {}, \
+                           inode {})", device, inode),
+            Comparison::ProbablySame =>
+                write!(f, "files are probably the same: sampled blocks are \
+                           the same"),
+            Comparison::SameText { rules } => {
+                write!(f, "files are the same as text, ignoring differences \
+                           in ")?;
+                for (i, rule) in rules.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", rule.name())?;
+                }
+                Ok(())
+            },
             Comparison::DifferentSize { left, len_left, right, len_right } =>
                 write!(f, "files have different sizes: \
                            {:?}: {} B -- {:?}: {} B",
                        left, len_left, right, len_right),
-            Comparison::DifferentContents { left, right } =>
-                write!(f, "files have different contents: {:?} -- {:?}",
+            Comparison::DifferentRange { left, len_left, right, len_right } =>
+                write!(f, "compared ranges have different lengths: \
+                           {:?}: {} B -- {:?}: {} B",
+                       left, len_left, right, len_right),
+            Comparison::DifferentLength { left, right, len, left_ended } => {
+                let (len_left, len_right) = if *left_ended {
+                    (format!("{} B", len), format!("more than {} B", len))
+                } else {
+                    (format!("more than {} B", len), format!("{} B", len))
+                };
+                write!(f, "files have different sizes: {:?}: {} -- {:?}: {}",
+                       left, len_left, right, len_right)
+            },
+            Comparison::DifferentContents {
+                left, right, offset, byte_left, byte_right, line_column,
+            } => {
+                write!(f, "files have different contents: {:?} -- {:?}: \
+                           first difference at offset {}",
+                       left, right, offset)?;
+                if let Some((line, column)) = line_column {
+                    write!(f, " (line {}, column {})", line, column)?;
+                }
+                write!(f, ": {:#04x} -- {:#04x}", byte_left, byte_right)
+            },
+            Comparison::DifferentExtents {
+                left, right, extents, truncated,
+            } => {
+                write!(f, "files have different contents: {:?} -- {:?}: \
+                           differing ranges:",
+                       left, right)?;
+                for Extent { offset, len } in extents {
+                    write!(f, "\noffset {}, length {}", offset, len)?;
+                }
+                if *truncated {
+                    write!(f, "\n(more differing ranges not listed)")?;
+                }
+                Ok(())
+            },
+            Comparison::DifferentHash { left, right } =>
+                write!(f, "files have different contents: {:?} -- {:?}: \
+                           cached hashes differ",
                        left, right),
+            Comparison::DifferentSample {
+                left, right, offset, byte_left, byte_right,
+            } =>
+                write!(f, "files have different contents: {:?} -- {:?}: \
+                           sampled difference at offset {}: \
+                           {:#04x} -- {:#04x}",
+                       left, right, offset, byte_left, byte_right),
+            Comparison::DifferentType { left, type_left, right, type_right } =>
+                write!(f, "files have different types: {:?}: {} -- {:?}: {}",
+                       left, file_type_to_string(type_left),
+                       right, file_type_to_string(type_right)),
+            Comparison::DifferentTarget {
+                left, target_left, right, target_right,
+            } =>
+                write!(f, "symbolic links have different targets: \
+                           {:?}: {:?} -- {:?}: {:?}",
+                       left, target_left, right, target_right),
+            Comparison::DifferentDigest { path, algorithm, expected, actual } =>
+                write!(f, "file has a different {} digest: {:?}: {} -- \
+                           expected: {}",
+                       algorithm.tag(), path, actual, expected),
+            Comparison::DifferentMetadata { left, right, differences } => {
+                write!(f, "files have different metadata: {:?} -- {:?}:",
+                       left, right)?;
+                for difference in differences {
+                    write!(f, "\n{}", difference)?;
+                }
+                Ok(())
+            },
+            Comparison::DifferentLine {
+                left, right, line, text_left, text_right,
+            } => {
+                write!(f, "files have different text: {:?} -- {:?}: first \
+                           difference at line {}: ",
+                       left, right, line)?;
+                for (i, text) in [text_left, text_right].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " -- ")?;
+                    }
+                    match text {
+                        Some(text) => write!(f, "{:?}", text)?,
+                        None => write!(f, "(end of file)")?,
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl ToJson for Comparison {
+    fn to_json(&self) -> Vec<Value> {
+        vec![match self {
+            Comparison::Same => json!({ "kind": "same" }),
+            Comparison::SameFile { device, inode } => json!({
+                "kind": "same",
+                "reason": "same_inode",
+                "device": device,
+                "inode": inode,
+            }),
+            Comparison::ProbablySame => json!({ "kind": "probably_same" }),
+            Comparison::SameText { rules } => json!({
+                "kind": "same",
+                "ignored": rules.iter()
+                    .map(|rule| rule.key())
+                    .collect::<Vec<_>>(),
+            }),
+            Comparison::DifferentSize { left, len_left, right, len_right } =>
+                json!({
+                    "kind": "different_size",
+                    "paths": [path_to_json(left), path_to_json(right)],
+                    "sizes": [len_left, len_right],
+                }),
+            Comparison::DifferentRange { left, len_left, right, len_right } =>
+                json!({
+                    "kind": "different_range_size",
+                    "paths": [path_to_json(left), path_to_json(right)],
+                    "lengths": [len_left, len_right],
+                }),
+            Comparison::DifferentLength { left, right, len, left_ended } => {
+                // only the size of the stream which ended first is known
+                let (size_left, size_right) = if *left_ended {
+                    (Value::from(*len), Value::Null)
+                } else {
+                    (Value::Null, Value::from(*len))
+                };
+                json!({
+                    "kind": "different_size",
+                    "paths": [path_to_json(left), path_to_json(right)],
+                    "sizes": [size_left, size_right],
+                })
+            },
+            Comparison::DifferentContents {
+                left, right, offset, byte_left, byte_right, line_column,
+            } => json!({
+                "kind": "different_contents",
+                "paths": [path_to_json(left), path_to_json(right)],
+                "sizes": [size_to_json(left), size_to_json(right)],
+                "offset": offset,
+                "bytes": [byte_left, byte_right],
+                "line": line_column.map(|(line, _)| line),
+                "column": line_column.map(|(_, column)| column),
+            }),
+            Comparison::DifferentExtents {
+                left, right, extents, truncated,
+            } => json!({
+                "kind": "different_contents",
+                "paths": [path_to_json(left), path_to_json(right)],
+                "sizes": [size_to_json(left), size_to_json(right)],
+                "offset": extents.first().map(|extent| extent.offset),
+                "ranges": extents.iter()
+                    .map(|Extent { offset, len }| json!({
+                        "offset": offset,
+                        "length": len,
+                    }))
+                    .collect::<Vec<_>>(),
+                "truncated": truncated,
+            }),
+            Comparison::DifferentHash { left, right } => json!({
+                "kind": "different_hash",
+                "paths": [path_to_json(left), path_to_json(right)],
+                "sizes": [size_to_json(left), size_to_json(right)],
+            }),
+            Comparison::DifferentSample {
+                left, right, offset, byte_left, byte_right,
+            } => json!({
+                "kind": "different_sample",
+                "paths": [path_to_json(left), path_to_json(right)],
+                "sizes": [size_to_json(left), size_to_json(right)],
+                "offset": offset,
+                "bytes": [byte_left, byte_right],
+            }),
+            Comparison::DifferentType { left, type_left, right, type_right } =>
+                json!({
+                    "kind": "different_type",
+                    "paths": [path_to_json(left), path_to_json(right)],
+                    "types": [file_type_to_string(type_left),
+                              file_type_to_string(type_right)],
+                }),
+            Comparison::DifferentTarget {
+                left, target_left, right, target_right,
+            } => json!({
+                "kind": "different_target",
+                "paths": [path_to_json(left), path_to_json(right)],
+                "targets": [path_to_json(target_left),
+                            path_to_json(target_right)],
+            }),
+            Comparison::DifferentDigest { path, algorithm, expected, actual } =>
+                json!({
+                    "kind": "different_digest",
+                    "paths": [path_to_json(path)],
+                    "sizes": [size_to_json(path)],
+                    "algorithm": algorithm.to_string().to_lowercase(),
+                    "digest": actual,
+                    "expected": expected,
+                }),
+            Comparison::DifferentMetadata { left, right, differences } =>
+                json!({
+                    "kind": "different_metadata",
+                    "paths": [path_to_json(left), path_to_json(right)],
+                    "sizes": [size_to_json(left), size_to_json(right)],
+                    "attributes": differences.iter()
+                        .flat_map(|difference| difference.to_json())
+                        .collect::<Vec<_>>(),
+                }),
+            Comparison::DifferentLine {
+                left, right, line, text_left, text_right,
+            } => json!({
+                "kind": "different_line",
+                "paths": [path_to_json(left), path_to_json(right)],
+                "sizes": [size_to_json(left), size_to_json(right)],
+                "line": line,
+                "lines": [text_left, text_right],
+            }),
+        }]
+    }
+}
+
+/// The comparison of all of `paths`, or of each of them to the digest with
+/// its algorithm, which are printed along with it if they are the same.
+struct Compared<'a> {
+    paths: &'a [&'a path::Path],
+    comparison: &'a Comparison,
+    digest: Option<(hash::Algorithm, &'a str)>,
+}
+
+impl<'a> fmt::Display for Compared<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.comparison, self.digest) {
+            (Comparison::Same, Some((algorithm, _))) =>
+                write!(f, "files have the expected {} digest",
+                       algorithm.tag()),
+            (comparison, _) => comparison.fmt(f),
+        }
+    }
+}
+
+impl<'a> ToJson for Compared<'a> {
+    fn to_json(&self) -> Vec<Value> {
+        match self.comparison {
+            Comparison::Same | Comparison::SameFile { .. } |
+            Comparison::ProbablySame | Comparison::SameText { .. } => {
+                let mut result = json!({
+                    "kind": match self.comparison {
+                        Comparison::ProbablySame => "probably_same",
+                        _ => "same",
+                    },
+                    "paths": self.paths.iter()
+                        .map(|path| path_to_json(path))
+                        .collect::<Vec<_>>(),
+                    "sizes": self.paths.iter()
+                        .map(|path| size_to_json(path))
+                        .collect::<Vec<_>>(),
+                });
+                if let Comparison::SameFile { device, inode } = self.comparison
+                {
+                    result["reason"] = "same_inode".into();
+                    result["device"] = (*device).into();
+                    result["inode"] = (*inode).into();
+                }
+                if let Comparison::SameText { rules } = self.comparison {
+                    result["ignored"] = rules.iter()
+                        .map(|rule| rule.key())
+                        .collect::<Vec<_>>()
+                        .into();
+                }
+                if let Some((algorithm, digest)) = self.digest {
+                    result["algorithm"] =
+                        algorithm.to_string().to_lowercase().into();
+                    result["digest"] = digest.into();
+                }
+                vec![result]
+            },
+            comparison => comparison.to_json(),
         }
     }
 }
@@ -304,9 +1615,21 @@ impl fmt::Display for Comparison {
 impl From<Comparison> for ExitStatus {
     fn from(comparison: Comparison) -> Self {
         match comparison {
-            Comparison::Same => ExitStatus::Same,
+            Comparison::Same | Comparison::SameFile { .. } |
+            Comparison::SameText { .. } => ExitStatus::Same,
+            Comparison::ProbablySame => ExitStatus::ProbablySame,
             Comparison::DifferentSize { .. } |
-            Comparison::DifferentContents { .. } =>
+            Comparison::DifferentRange { .. } |
+            Comparison::DifferentLength { .. } |
+            Comparison::DifferentContents { .. } |
+            Comparison::DifferentExtents { .. } |
+            Comparison::DifferentHash { .. } |
+            Comparison::DifferentSample { .. } |
+            Comparison::DifferentType { .. } |
+            Comparison::DifferentTarget { .. } |
+            Comparison::DifferentDigest { .. } |
+            Comparison::DifferentMetadata { .. } |
+            Comparison::DifferentLine { .. } =>
                 ExitStatus::Different,
         }
     }
@@ -316,6 +1639,8 @@ impl From<Comparison> for ExitStatus {
 enum ExitStatus {
     Same,
     Different,
+    Incomplete,
+    ProbablySame,
     Err,
 }
 
@@ -324,6 +1649,9 @@ impl fmt::Display for ExitStatus {
         f.write_str(match self {
             ExitStatus::Same => "files have the same contents",
             ExitStatus::Different => "files have different contents",
+            ExitStatus::Incomplete => "some comparisons could not be made",
+            ExitStatus::ProbablySame =>
+                "files are probably the same, by sampled blocks",
             ExitStatus::Err => "an error occured",
         })
     }
@@ -334,6 +1662,8 @@ impl From<ExitStatus> for i32 {
         match exit_status {
             ExitStatus::Same => 0,
             ExitStatus::Different => 1,
+            ExitStatus::Incomplete => 2,
+            ExitStatus::ProbablySame => 3,
             ExitStatus::Err => i8::max_value().into(),
         }
     }
@@ -345,6 +1675,16 @@ quick_error!{
         Clap(e: clap::Error) {
             cause(e) description(e.description()) display("{}", e) from()
         }
+        ArchiveInvalid(path: path::PathBuf, e: io::Error) {
+            cause(e)
+            description("invalid archive")
+            display("invalid archive {:?}: {}", path, e)
+        }
+        DecompressFailed(path: path::PathBuf, e: io::Error) {
+            cause(e)
+            description("cannot decompress file")
+            display("cannot decompress {:?}: {}", path, e)
+        }
         FileAccessDenied(path: path::PathBuf, e: io::Error) {
             cause(e)
             description("access to file denied")
@@ -362,12 +1702,34 @@ quick_error!{
         Fmt(e: fmt::Error) {
             cause(e) description(e.description()) display("{}", e) from()
         }
+        ManifestInvalid(path: path::PathBuf, line: usize) {
+            description("invalid manifest")
+            display("invalid line {} in manifest: {:?}", line, path)
+        }
+        StdinRepeated {
+            description("standard input given more than once")
+            display("standard input given more than once")
+        }
         Io(e: io::Error) {
             cause(e) description(e.description()) display("{}", e)
         }
     }
 }
 
+impl Error {
+    /// The path of the file the error is about, if any.
+    fn path(&self) -> Option<&path::Path> {
+        match self {
+            Error::ArchiveInvalid(path, _) |
+            Error::DecompressFailed(path, _) |
+            Error::FileAccessDenied(path, _) |
+            Error::FileNotFound(path) |
+            Error::FileTypeUnsupported(path, _) => Some(path),
+            _ => None,
+        }
+    }
+}
+
 fn file_type_to_string(file_type: &fs::FileType) -> String {
     if file_type.is_dir() {
         format!("directory")
@@ -0,0 +1,423 @@
+use std::collections::BTreeSet;
+use std::io::prelude::*;
+use std::fmt::Write as WriteFmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::{ffi, fmt, fs, io, path, str};
+use serde_json::Value;
+use hash::{self, Algorithm};
+use output::{path_to_json, ToJson};
+use {keep_going, tree, Error, ExitStatus, Options, Res};
+
+/// The checksums of the regular files under a directory, in the format of
+/// `sha256sum` and `b3sum`.
+pub struct Manifest {
+    entries: Vec<Entry>,
+    tag: bool,
+}
+
+struct Entry {
+    /// The path relative to the directory.
+    path: path::PathBuf,
+    algorithm: Algorithm,
+    hash: String,
+}
+
+pub fn create(dir: &path::Path, algorithm: Algorithm, tag: bool,
+              options: &Options, errors: &mut Vec<Error>) -> Res<Manifest>
+{
+    // when redirected to a file under the directory, the manifest would
+    // otherwise list itself, with the checksum of an empty file
+    let stdout = fs::metadata("/dev/stdout").ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| (metadata.dev(), metadata.ino()));
+    let paths = regular_files(dir, stdout, options, errors)?;
+
+    let files = paths.iter()
+        .map(|path| (dir.join(path), algorithm))
+        .collect::<Vec<_>>();
+    let mut entries = Vec::with_capacity(paths.len());
+    let hashes = hash::hash_files(&files, options);
+    for (path, hash) in paths.into_iter().zip(hashes) {
+        if let Some(hash) = keep_going(hash, options, errors)? {
+            entries.push(Entry { path, algorithm, hash });
+        }
+    }
+    Ok(Manifest { entries, tag })
+}
+
+/// Check the regular files under `dir` against the manifest at `manifest`;
+/// `algorithm` is that of the lines not naming theirs.
+pub fn verify(manifest: &path::Path, dir: &path::Path, algorithm: Algorithm,
+              options: &Options, errors: &mut Vec<Error>) -> Res<Verification>
+{
+    let metadata = fs::metadata(manifest).map_err(|e| (e, manifest))?;
+    let entries = parse(manifest, algorithm)?;
+
+    let files = entries.iter()
+        .map(|entry| (dir.join(&entry.path), entry.algorithm))
+        .collect::<Vec<_>>();
+    let mut changes = Vec::new();
+    let hashes = hash::hash_files(&files, options);
+    for (entry, hash) in entries.iter().zip(hashes) {
+        let change = match hash {
+            Ok(ref hash) if *hash == entry.hash => continue,
+            Ok(_) => Change::Changed,
+            Err(Error::FileNotFound(_)) => Change::Missing,
+            Err(e) => {
+                keep_going::<()>(Err(e), options, errors)?;
+                continue;
+            },
+        };
+        changes.push((change, entry.path.clone()));
+    }
+
+    let listed = entries.iter()
+        .map(|entry| entry.path.as_path())
+        .collect::<BTreeSet<_>>();
+    let id = (metadata.dev(), metadata.ino());
+    for path in regular_files(dir, Some(id), options, errors)? {
+        if !listed.contains(path.as_path()) {
+            changes.push((Change::New, path));
+        }
+    }
+    Ok(Verification { changes })
+}
+
+/// Find the regular files under `dir`, relative to it, except the one with the
+/// device and inode numbers `exclude`.
+fn regular_files(dir: &path::Path, exclude: Option<(u64, u64)>,
+                 options: &Options, errors: &mut Vec<Error>) ->
+    Res<Vec<path::PathBuf>>
+{
+    let mut files = Vec::new();
+    for (path, file_type) in tree::walk(dir, options, errors)?.types {
+        if !file_type.is_file() {
+            continue;
+        }
+        if let Some(exclude) = exclude {
+            let path_full = dir.join(&path);
+            let metadata = fs::metadata(&path_full)
+                .map_err(|e| Error::from((e, &path_full)));
+            match keep_going(metadata, options, errors)? {
+                Some(ref metadata)
+                    if (metadata.dev(), metadata.ino()) != exclude => (),
+                _ => continue,
+            }
+        }
+        files.push(path);
+    }
+    Ok(files)
+}
+
+fn parse(manifest: &path::Path, algorithm: Algorithm) -> Res<Vec<Entry>> {
+    let file = fs::File::open(manifest).map_err(|e| (e, manifest))?;
+    let mut entries = Vec::new();
+    // lines are read as bytes, as paths need not be UTF-8
+    for (i, line) in io::BufReader::new(file).split(b'\n').enumerate() {
+        let line = line.map_err(|e| (e, manifest))?;
+        if line.is_empty() {
+            continue;
+        }
+        match parse_line(&line, algorithm) {
+            Some(entry) => entries.push(entry),
+            None => return Err(Error::ManifestInvalid(manifest.into(), i + 1)),
+        }
+    }
+    Ok(entries)
+}
+
+/// Parse a line either in the default format of `sha256sum`, a hash followed
+/// by a space, a space or an asterisk, and the path, or in the tagged format,
+/// `SHA256 (path) = hash`; a leading backslash means the path is escaped.
+/// Paths need not be UTF-8, and their `.` components are left out.
+fn parse_line(line: &[u8], algorithm: Algorithm) -> Option<Entry> {
+    let (line, escaped) = match line.strip_prefix(b"\\") {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+
+    let tagged = [Algorithm::Sha256, Algorithm::Sha512, Algorithm::Blake3]
+        .iter()
+        .filter_map(|&algorithm| {
+            let rest = line.strip_prefix(algorithm.tag().as_bytes())?
+                .strip_prefix(b" (")?;
+            let end = rest.windows(4).rposition(|w| w == b") = ")?;
+            Some((&rest[..end], algorithm, &rest[end + 4..]))
+        })
+        .next();
+    let (path, algorithm, hash) = match tagged {
+        Some(tagged) => tagged,
+        None => {
+            let end = line.iter().position(|&b| b == b' ')?;
+            let path = match line[end + 1..].split_first()? {
+                (b' ', path) | (b'*', path) => path,
+                _ => return None,
+            };
+            (path, algorithm, &line[..end])
+        },
+    };
+
+    let hash = str::from_utf8(hash).ok()?;
+    if hash.len() != algorithm.hex_len() ||
+        !hash.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    let path = if escaped { unescape(path)? } else { path.to_vec() };
+    Some(Entry {
+        path: path::Path::new(&ffi::OsString::from_vec(path)).components()
+            .filter(|component| *component != path::Component::CurDir)
+            .collect(),
+        algorithm,
+        hash: hash.to_ascii_lowercase(),
+    })
+}
+
+/// Undo the escaping of `escape`.
+fn unescape(path: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&b) = bytes.next() {
+        unescaped.push(match b {
+            b'\\' => match bytes.next()? {
+                b'\\' => b'\\',
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b'x' => {
+                    let hex = [*bytes.next()?, *bytes.next()?];
+                    u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?
+                },
+                _ => return None,
+            },
+            b => b,
+        });
+    }
+    Some(unescaped)
+}
+
+/// Escape a path the same way as `sha256sum`, and its bytes which are not
+/// UTF-8 as `\xNN`, which it leaves as they are; return whether it was.
+fn escape(path: &path::Path) -> (String, bool) {
+    let bytes = path.as_os_str().as_bytes();
+    let mut escaped = String::with_capacity(bytes.len());
+    let mut any = false;
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c => {
+                    escaped.push(c);
+                    continue;
+                },
+            }
+            any = true;
+        }
+        for b in chunk.invalid() {
+            write!(escaped, "\\x{:02x}", b).expect("cannot write to string");
+            any = true;
+        }
+    }
+    (escaped, any)
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let (path, escaped) = escape(&entry.path);
+            if escaped {
+                write!(f, "\\")?;
+            }
+            if self.tag {
+                write!(f, "{} ({}) = {}",
+                       entry.algorithm.tag(), path, entry.hash)?;
+            } else {
+                write!(f, "{}  {}", entry.hash, path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ToJson for Manifest {
+    fn to_json(&self) -> Vec<Value> {
+        self.entries.iter()
+            .map(|entry| json!({
+                "kind": "checksum",
+                "path": path_to_json(&entry.path),
+                "algorithm": entry.algorithm.to_string().to_lowercase(),
+                "hash": entry.hash,
+            }))
+            .collect()
+    }
+}
+
+impl From<Manifest> for ExitStatus {
+    fn from(_: Manifest) -> Self {
+        ExitStatus::Same
+    }
+}
+
+pub struct Verification {
+    changes: Vec<(Change, path::PathBuf)>,
+}
+
+enum Change {
+    Changed,
+    Missing,
+    New,
+}
+
+impl Change {
+    fn name(&self) -> &'static str {
+        match self {
+            Change::Changed => "changed",
+            Change::Missing => "missing",
+            Change::New => "new",
+        }
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "files match the manifest");
+        }
+        for (i, (change, path)) in self.changes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {:?}", change.name(), path)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToJson for Verification {
+    fn to_json(&self) -> Vec<Value> {
+        if self.changes.is_empty() {
+            return vec![json!({ "kind": "same" })];
+        }
+        self.changes.iter()
+            .map(|(change, path)| json!({
+                "kind": change.name(),
+                "path": path_to_json(path),
+            }))
+            .collect()
+    }
+}
+
+impl From<Verification> for ExitStatus {
+    fn from(verification: Verification) -> Self {
+        if verification.changes.is_empty() {
+            ExitStatus::Same
+        } else {
+            ExitStatus::Different
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str =
+        "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    /// The path, algorithm and hash parsed from `line`.
+    fn parse(line: &str, algorithm: Algorithm) ->
+        Option<(path::PathBuf, Algorithm, String)>
+    {
+        parse_line(line.as_bytes(), algorithm)
+            .map(|entry| (entry.path, entry.algorithm, entry.hash))
+    }
+
+    #[test]
+    fn parse_line_default() {
+        let entry = |path: &str| {
+            Some((path::PathBuf::from(path), Algorithm::Sha256,
+                  HASH.to_string()))
+        };
+        assert_eq!(parse(&format!("{}  a b", HASH), Algorithm::Sha256),
+                   entry("a b"));
+        assert_eq!(parse(&format!("{} *a", HASH), Algorithm::Sha256),
+                   entry("a"));
+        assert_eq!(parse(&format!("{}  a", HASH.to_uppercase()),
+                         Algorithm::Sha256),
+                   entry("a"));
+        assert_eq!(parse(&format!("\\{}  a\\nb\\\\c", HASH),
+                         Algorithm::Sha256),
+                   entry("a\nb\\c"));
+        // a path which only looks escaped
+        assert_eq!(parse(&format!("{}  a\\nb", HASH), Algorithm::Sha256),
+                   entry("a\\nb"));
+        assert_eq!(parse(&format!("{}  ./a/./b", HASH), Algorithm::Sha256),
+                   entry("a/b"));
+    }
+
+    #[test]
+    fn parse_line_bytes() {
+        let path = path::PathBuf::from(ffi::OsString::from_vec(
+            b"a\xff\nb".to_vec()));
+        let mut line = format!("{}  a", HASH).into_bytes();
+        line.extend(b"\xff\nb");
+        assert_eq!(parse_line(&line, Algorithm::Sha256).map(|e| e.path),
+                   Some(path.clone()));
+        let line = format!("\\{}  a\\xff\\nb", HASH);
+        assert_eq!(parse(&line, Algorithm::Sha256).map(|e| e.0),
+                   Some(path.clone()));
+        assert_eq!(escape(&path), ("a\\xff\\nb".to_string(), true));
+        assert_eq!(escape(path::Path::new("a b")), ("a b".to_string(), false));
+    }
+
+    #[test]
+    fn parse_line_tagged() {
+        assert_eq!(parse(&format!("BLAKE3 (a) = {}", HASH),
+                         Algorithm::Sha256),
+                   Some((path::PathBuf::from("a"), Algorithm::Blake3,
+                         HASH.to_string())));
+        assert_eq!(parse(&format!("SHA256 (a) = b) = {}", HASH),
+                         Algorithm::Blake3),
+                   Some((path::PathBuf::from("a) = b"), Algorithm::Sha256,
+                         HASH.to_string())));
+        assert_eq!(parse(&format!("\\SHA256 (a\\r\\n) = {}", HASH),
+                         Algorithm::Sha256),
+                   Some((path::PathBuf::from("a\r\n"), Algorithm::Sha256,
+                         HASH.to_string())));
+        // a hash too short for the algorithm in the tag
+        assert_eq!(parse(&format!("SHA512 (a) = {}", HASH),
+                         Algorithm::Sha256),
+                   None);
+    }
+
+    #[test]
+    fn parse_line_invalid() {
+        for line in &["", HASH, &format!("{} a", HASH),
+                      &format!("{}  a", &HASH[1..]),
+                      &format!("{}  a", HASH.replace('9', "g")),
+                      &format!("\\{}  a\\tb", HASH),
+                      &format!("\\{}  a\\", HASH),
+                      &format!("SHA256 (a) {}", HASH),
+                      &format!("MD5 (a) = {}", HASH)]
+        {
+            assert_eq!(parse(line, Algorithm::Sha256), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn unescape_escapes() {
+        assert_eq!(unescape(b"a\\\\b\\nc\\r").as_deref(),
+                   Some(&b"a\\b\nc\r"[..]));
+        assert_eq!(unescape(b"\\x00\\xFe").as_deref(), Some(&[0, 0xfe][..]));
+        assert_eq!(unescape(b"").as_deref(), Some(&b""[..]));
+        assert_eq!(unescape(b"\\t"), None);
+        assert_eq!(unescape(b"a\\"), None);
+        assert_eq!(unescape(b"\\x1"), None);
+        assert_eq!(unescape(b"\\x1g"), None);
+    }
+}
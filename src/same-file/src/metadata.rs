@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use serde_json::Value;
+use std::{fmt, fs, io, path, ptr, str};
+use libc;
+use output::ToJson;
+use {Comparison, Res};
+
+/// The extended attributes holding POSIX ACLs, which are compared as ACLs.
+const ACL_ACCESS: &str = "system.posix_acl_access";
+const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+#[derive(Clone, Copy, Debug, EnumString, PartialEq)]
+pub enum Attribute {
+    #[strum(serialize = "mode")]
+    Mode,
+    #[strum(serialize = "owner")]
+    Owner,
+    #[strum(serialize = "mtime")]
+    Mtime,
+    #[strum(serialize = "xattr")]
+    Xattr,
+    #[strum(serialize = "acl")]
+    Acl,
+}
+
+impl Attribute {
+    fn name(self) -> &'static str {
+        match self {
+            Attribute::Mode => "mode",
+            Attribute::Owner => "owner",
+            Attribute::Mtime => "mtime",
+            Attribute::Xattr => "xattr",
+            Attribute::Acl => "acl",
+        }
+    }
+}
+
+/// An attribute, or with `name` one of several of its kind, with a different
+/// value in each file; `None` if a file has no such attribute.
+pub struct Difference {
+    attribute: Attribute,
+    name: Option<String>,
+    left: Option<String>,
+    right: Option<String>,
+}
+
+/// Compare the `attributes` of the files at `left` and `right`, or with
+/// `follow`, of the files symbolic links among them point to.
+pub fn compare(left: &path::Path, right: &path::Path,
+               attributes: &[Attribute], follow: bool) ->
+    Res<Option<Comparison>>
+{
+    if attributes.is_empty() {
+        return Ok(None);
+    }
+    let (metadata_left, metadata_right) = if follow {
+        (fs::metadata(left).map_err(|e| (e, left))?,
+         fs::metadata(right).map_err(|e| (e, right))?)
+    } else {
+        (fs::symlink_metadata(left).map_err(|e| (e, left))?,
+         fs::symlink_metadata(right).map_err(|e| (e, right))?)
+    };
+
+    let mut differences = Vec::new();
+    let mut push = |attribute, name: Option<&str>, values_left, values_right| {
+        if values_left != values_right {
+            differences.push(Difference {
+                attribute,
+                name: name.map(String::from),
+                left: values_left,
+                right: values_right,
+            });
+        }
+    };
+    for &attribute in attributes {
+        match attribute {
+            Attribute::Mode => push(attribute, None,
+                                    Some(mode(&metadata_left)),
+                                    Some(mode(&metadata_right))),
+            Attribute::Owner => push(attribute, None,
+                                     Some(owner(&metadata_left)),
+                                     Some(owner(&metadata_right))),
+            Attribute::Mtime => push(attribute, None,
+                                     Some(mtime(&metadata_left)),
+                                     Some(mtime(&metadata_right))),
+            Attribute::Xattr | Attribute::Acl => {
+                let mut xattrs_left = xattrs(left, follow)?;
+                let mut xattrs_right = xattrs(right, follow)?;
+                let names = xattrs_left.keys().chain(xattrs_right.keys())
+                    .filter(|&name| {
+                        let acl = name == ACL_ACCESS || name == ACL_DEFAULT;
+                        acl == (attribute == Attribute::Acl)
+                    })
+                    .cloned()
+                    .collect::<BTreeSet<_>>();
+                for name in &names {
+                    let (value_left, value_right) =
+                        (xattrs_left.remove(name), xattrs_right.remove(name));
+                    let (to_string, name): (fn(&[u8]) -> String, &str) =
+                        match attribute {
+                            Attribute::Acl if name == ACL_ACCESS =>
+                                (acl_to_string, "access"),
+                            Attribute::Acl => (acl_to_string, "default"),
+                            _ => (value_to_string, name),
+                        };
+                    push(attribute, Some(name),
+                         value_left.as_deref().map(to_string),
+                         value_right.as_deref().map(to_string));
+                }
+            },
+        }
+    }
+
+    if differences.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Comparison::DifferentMetadata {
+        left: left.into(), right: right.into(), differences,
+    }))
+}
+
+fn mode(metadata: &fs::Metadata) -> String {
+    format!("{:04o}", metadata.mode() & 0o7777)
+}
+
+fn owner(metadata: &fs::Metadata) -> String {
+    format!("{}:{}", metadata.uid(), metadata.gid())
+}
+
+fn mtime(metadata: &fs::Metadata) -> String {
+    format!("{}.{:09}", metadata.mtime(), metadata.mtime_nsec())
+}
+
+/// Read the extended attributes of the file at `path`, by name; a filesystem
+/// not supporting them has none.
+fn xattrs(path: &path::Path, follow: bool) ->
+    Res<BTreeMap<String, Vec<u8>>>
+{
+    let path_c = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| (io::Error::from(e), path))?;
+    let names = read_sized(|buf, len| unsafe {
+        if follow {
+            libc::listxattr(path_c.as_ptr(), buf, len)
+        } else {
+            libc::llistxattr(path_c.as_ptr(), buf, len)
+        }
+    });
+    let names = match names {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) =>
+            return Ok(BTreeMap::new()),
+        Err(e) => return Err((e, path).into()),
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty())
+    {
+        let name_c = CString::new(name).expect("NUL byte in attribute name");
+        let value = read_sized(|buf, len| unsafe {
+            if follow {
+                libc::getxattr(path_c.as_ptr(), name_c.as_ptr(),
+                               buf as *mut libc::c_void, len)
+            } else {
+                libc::lgetxattr(path_c.as_ptr(), name_c.as_ptr(),
+                                buf as *mut libc::c_void, len)
+            }
+        });
+        match value {
+            Ok(value) => {
+                xattrs.insert(String::from_utf8_lossy(name).into_owned(),
+                              value);
+            },
+            // removed since listed
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => (),
+            Err(e) => return Err((e, path).into()),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Call `read` first for the size of the value it reads, then for the value,
+/// and again if it grew in between.
+fn read_sized<F>(read: F) -> io::Result<Vec<u8>>
+    where F: Fn(*mut libc::c_char, libc::size_t) -> libc::ssize_t
+{
+    loop {
+        let len = read(ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = read(buf.as_mut_ptr() as *mut libc::c_char, buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+/// Format the value of an extended attribute as a quoted string if it is
+/// text, and in hexadecimal otherwise.
+fn value_to_string(value: &[u8]) -> String {
+    match str::from_utf8(value) {
+        Ok(text) => format!("{:?}", text),
+        Err(_) => {
+            let hex = value.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+            format!("0x{}", hex)
+        },
+    }
+}
+
+/// Format a POSIX ACL in the extended attribute format of Linux in the short
+/// text form of `getfacl -c`, e.g. `user::rw-,group::r--,other::r--`.
+fn acl_to_string(value: &[u8]) -> String {
+    // a version followed by entries of a tag, permissions and an ID
+    const VERSION: u32 = 2;
+    let le_u16 = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]);
+    let le_u32 = |bytes: &[u8]| {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) ||
+        le_u32(value) != VERSION
+    {
+        return value_to_string(value);
+    }
+
+    let mut entries = Vec::new();
+    for entry in value[4..].chunks(8) {
+        let (tag, perm, id) =
+            (le_u16(&entry[0..]), le_u16(&entry[2..]), le_u32(&entry[4..]));
+        let qualifier = match tag {
+            0x01 => "user::".to_string(),
+            0x02 => format!("user:{}:", id),
+            0x04 => "group::".to_string(),
+            0x08 => format!("group:{}:", id),
+            0x10 => "mask::".to_string(),
+            0x20 => "other::".to_string(),
+            _ => return value_to_string(value),
+        };
+        let perm = [(4, 'r'), (2, 'w'), (1, 'x')].iter()
+            .map(|&(bit, c)| if perm & bit != 0 { c } else { '-' })
+            .collect::<String>();
+        entries.push(qualifier + &perm);
+    }
+    entries.join(",")
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.attribute.name())?;
+        if let Some(ref name) = self.name {
+            write!(f, " {}", name)?;
+        }
+        write!(f, ": {} -- {}",
+               self.left.as_deref().unwrap_or("(none)"),
+               self.right.as_deref().unwrap_or("(none)"))
+    }
+}
+
+impl ToJson for Difference {
+    fn to_json(&self) -> Vec<Value> {
+        vec![json!({
+            "attribute": self.attribute.name(),
+            "name": self.name,
+            "values": [self.left, self.right],
+        })]
+    }
+}
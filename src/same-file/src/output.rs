@@ -0,0 +1,132 @@
+use serde_json::{Map, Value};
+use std::{fmt, fs, path};
+use {stream, Error, ExitStatus, Res};
+
+/// The version of the JSON output, incremented whenever fields are removed or
+/// change their meaning; fields may be added without incrementing it.
+pub const VERSION: u64 = 1;
+
+#[derive(Clone, Copy, EnumString)]
+pub enum Format {
+    #[strum(serialize = "text")]
+    Text,
+    #[strum(serialize = "json")]
+    Json,
+    #[strum(serialize = "ndjson")]
+    Ndjson,
+}
+
+/// Results which can be printed as JSON, as one object per result.
+pub trait ToJson {
+    fn to_json(&self) -> Vec<Value>;
+}
+
+/// Prints results as text or JSON as they are found, or, for a single JSON
+/// document, once all of them are.
+pub struct Output {
+    format: Format,
+    quiet: bool,
+    mode: &'static str,
+    results: Vec<Value>,
+    incomplete: bool,
+}
+
+impl Output {
+    pub fn new(format: Format, quiet: bool, mode: &'static str) -> Self {
+        Output { format, quiet, mode, results: Vec::new(), incomplete: false }
+    }
+
+    /// Print the errors skipped over with --keep-going, to stderr unless they
+    /// are printed as JSON.
+    pub fn errors(&mut self, errors: &mut Vec<Error>) {
+        for e in errors.drain(..) {
+            self.incomplete = true;
+            match (self.format, self.quiet) {
+                (Format::Text, _) | (_, true) => eprintln!("{}", e),
+                (Format::Json, false) => self.results.extend(e.to_json()),
+                (Format::Ndjson, false) => for result in e.to_json() {
+                    println!("{}", self.header(result));
+                },
+            }
+        }
+    }
+
+    /// Whether any errors were skipped over.
+    pub fn incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    pub fn print<T: fmt::Display + ToJson>(&mut self, value: &T) {
+        if self.quiet {
+            return;
+        }
+        match self.format {
+            Format::Text => println!("{}", value),
+            Format::Json => self.results.extend(value.to_json()),
+            Format::Ndjson => for result in value.to_json() {
+                println!("{}", self.header(result));
+            },
+        }
+    }
+
+    /// Print the JSON document, if any; errors are printed as results too,
+    /// unless no output is printed at all.
+    pub fn finish(mut self, result: Res<ExitStatus>) -> Res<ExitStatus> {
+        let status = match (self.format, result) {
+            (Format::Text, result) => return result,
+            (_, Err(ref e)) if !self.quiet => {
+                let error = e.to_json();
+                match self.format {
+                    Format::Json => self.results.extend(error),
+                    _ => for result in error {
+                        println!("{}", self.header(result));
+                    },
+                }
+                ExitStatus::Err
+            },
+            (_, result) => result?,
+        };
+        if let (Format::Json, false) = (self.format, self.quiet) {
+            let mut document = self.header(Value::Object(Map::new()));
+            document["results"] = Value::Array(self.results);
+            println!("{}", document);
+        }
+        Ok(status)
+    }
+
+    /// Prefix `result` with the version and the mode.
+    fn header(&self, result: Value) -> Value {
+        let mut object = Map::new();
+        object.insert("version".into(), VERSION.into());
+        object.insert("mode".into(), self.mode.into());
+        if let Value::Object(result) = result {
+            object.extend(result);
+        }
+        Value::Object(object)
+    }
+}
+
+impl ToJson for Error {
+    fn to_json(&self) -> Vec<Value> {
+        vec![json!({
+            "kind": "error",
+            "message": self.to_string(),
+            "path": self.path().map(path_to_json),
+        })]
+    }
+}
+
+pub fn path_to_json(path: &path::Path) -> Value {
+    path.to_string_lossy().into()
+}
+
+/// The size of the regular file at `path`, or null for other files.
+pub fn size_to_json(path: &path::Path) -> Value {
+    if stream::is_stdin(path) {
+        return Value::Null;
+    }
+    match fs::metadata(path) {
+        Ok(ref metadata) if metadata.is_file() => metadata.len().into(),
+        _ => Value::Null,
+    }
+}
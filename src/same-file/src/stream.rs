@@ -0,0 +1,225 @@
+use std::io::prelude::*;
+use std::os::unix::fs::FileTypeExt;
+use std::{cmp, fs, io, path};
+use flate2;
+use xz2;
+use zstd;
+use {Comparison, Error, Extent, Options, Res};
+
+/// The magic bytes each compressed file starts with, for each format which can
+/// be decompressed.
+const MAGIC_GZIP: &[u8] = b"\x1f\x8b";
+const MAGIC_XZ: &[u8] = b"\xfd7zXZ\x00";
+const MAGIC_ZSTD: &[u8] = b"\x28\xb5\x2f\xfd";
+
+/// Whether `path` stands for the standard input.
+pub fn is_stdin(path: &path::Path) -> bool {
+    path == path::Path::new("-")
+}
+
+/// Whether files of `file_type` can only be read sequentially, and have no
+/// size known in advance.
+pub fn is_stream(file_type: &fs::FileType) -> bool {
+    file_type.is_fifo() || file_type.is_char_device()
+}
+
+/// Whether `path` is the standard input or a file which is read as a stream.
+pub fn is_stream_path(path: &path::Path) -> bool {
+    is_stdin(path) ||
+        fs::metadata(path).map(|m| is_stream(&m.file_type())).unwrap_or(false)
+}
+
+/// Compare the first of `paths` to each of the others by reading all of them
+/// in a single sequential pass, for when some of them are streams or are
+/// decompressed.
+pub fn compare_streams(paths: &[&path::Path], options: &Options,
+                       differences_max: Option<usize>) -> Res<Comparison>
+{
+    if paths.iter().filter(|&&path| is_stdin(path)).count() > 1 {
+        return Err(Error::StdinRepeated);
+    }
+    let mut readers = paths.iter().enumerate()
+        .map(|(i, &path)| {
+            let skip = if i == 0 {
+                options.skip_left
+            } else {
+                options.skip_right
+            };
+            open(path, skip, options)
+        })
+        .collect::<Res<Vec<_>>>()?;
+    let mut bufs = vec![vec![0; options.buffer_size]; paths.len()];
+    let mut lens = vec![0; paths.len()];
+    let mut extents = paths.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+    let first = paths[0];
+
+    let mut pos = 0;
+    loop {
+        for (i, (reader, buf)) in readers.iter_mut().zip(&mut bufs).enumerate()
+        {
+            lens[i] = read_full(reader.as_mut(), buf)
+                .map_err(|e| read_error(e, paths[i], options))?;
+        }
+
+        let (buf_first, bufs_rest) = bufs.split_first()
+            .expect("no first element in bufs");
+        for (i, buf) in bufs_rest.iter().enumerate().map(|(i, b)| (i + 1, b)) {
+            let len = cmp::min(lens[0], lens[i]);
+            let (left, right) = (&buf_first[..len], &buf[..len]);
+            match differences_max {
+                None => if left != right {
+                    let offset = left.iter().zip(right)
+                        .position(|(l, r)| l != r)
+                        .expect("no difference in different buffers");
+                    return Ok(Comparison::DifferentContents {
+                        left: first.into(), right: paths[i].into(),
+                        offset: pos + offset as u64,
+                        byte_left: left[offset], byte_right: right[offset],
+                        line_column: None,
+                    });
+                },
+                Some(differences_max) => {
+                    push_extents(&mut extents[i], pos, left, right);
+                    if extents[i].len() > differences_max {
+                        extents[i].truncate(differences_max);
+                        return Ok(Comparison::DifferentExtents {
+                            left: first.into(), right: paths[i].into(),
+                            extents: extents.swap_remove(i), truncated: true,
+                        });
+                    }
+                },
+            }
+            if lens[i] != lens[0] {
+                return Ok(Comparison::DifferentLength {
+                    left: first.into(), right: paths[i].into(),
+                    len: pos + len as u64, left_ended: lens[0] < lens[i],
+                });
+            }
+        }
+
+        // a short read means the end of every stream, as all of them had the
+        // same length
+        if lens[0] < options.buffer_size {
+            break;
+        }
+        pos += lens[0] as u64;
+    }
+
+    match extents.iter().position(|extents| !extents.is_empty()) {
+        Some(i) => Ok(Comparison::DifferentExtents {
+            left: first.into(), right: paths[i].into(),
+            extents: extents.swap_remove(i), truncated: false,
+        }),
+        None => Ok(Comparison::Same),
+    }
+}
+
+/// Open the file at `path` as a stream, decompressed with --decompress, past
+/// its first `skip` bytes and up to the number of bytes to compare of
+/// `options`.
+fn open(path: &path::Path, skip: u64, options: &Options) ->
+    Res<Box<dyn Read>>
+{
+    let reader: Box<dyn Read> = if is_stdin(path) {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(path).map_err(|e| (e, path))?)
+    };
+    let mut reader = if options.decompress {
+        decompress(path, reader, options)?
+    } else {
+        if options.verbose {
+            eprintln!("{:?}: reading as a stream", path);
+        }
+        reader
+    };
+    // streams cannot seek, so the skipped bytes are read and dropped
+    io::copy(&mut reader.by_ref().take(skip), &mut io::sink())
+        .map_err(|e| read_error(e, path, options))?;
+    Ok(Box::new(reader.take(options.bytes.unwrap_or(u64::MAX))))
+}
+
+/// Decompress `reader`, read from `path`, if it starts with the magic bytes of
+/// a known compression format, and otherwise read it as it is.
+pub fn decompress(path: &path::Path, mut reader: Box<dyn Read>,
+                  options: &Options) -> Res<Box<dyn Read>>
+{
+    let mut magic = vec![0; MAGIC_XZ.len()];
+    let len = read_full(reader.as_mut(), &mut magic)
+        .map_err(|e| (e, path))?;
+    magic.truncate(len);
+    let format = [("gzip", MAGIC_GZIP), ("xz", MAGIC_XZ), ("zstd", MAGIC_ZSTD)]
+        .iter()
+        .find(|&&(_, bytes)| magic.starts_with(bytes))
+        .map(|&(format, _)| format);
+    if options.verbose {
+        match format {
+            Some(format) => eprintln!("{:?}: decompressing {}", path, format),
+            None => eprintln!("{:?}: not compressed, reading as a stream",
+                              path),
+        }
+    }
+
+    // the magic bytes are read again by the decoder
+    let reader = io::BufReader::with_capacity(options.buffer_size,
+                                              io::Cursor::new(magic)
+                                                  .chain(reader));
+    Ok(match format {
+        Some("gzip") =>
+            Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Some("xz") =>
+            Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+        Some("zstd") =>
+            Box::new(zstd::Decoder::with_buffer(reader)
+                     .map_err(|e| read_error(e, path, options))?),
+        _ => Box::new(reader),
+    })
+}
+
+/// The error for `e`, from reading the file at `path`; with --decompress,
+/// invalid data, an early end and other errors not from reading files are
+/// errors in its compressed contents.
+fn read_error(e: io::Error, path: &path::Path, options: &Options) -> Error {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput |
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::Other
+            if options.decompress =>
+            Error::DecompressFailed(path.into(), e),
+        _ => (e, path).into(),
+    }
+}
+
+/// Read into `buf` until it is full or the end of `reader` is reached, and
+/// return the number of bytes read.
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Append the ranges at which `left` and `right`, read at `pos`, differ to
+/// `extents`, merging them with the last one if it is contiguous.
+fn push_extents(extents: &mut Vec<Extent>, pos: u64, left: &[u8],
+                right: &[u8])
+{
+    if left == right {
+        return;
+    }
+    for (i, (l, r)) in left.iter().zip(right).enumerate() {
+        if l == r {
+            continue;
+        }
+        let offset = pos + i as u64;
+        match extents.last_mut() {
+            Some(last) if last.offset + last.len == offset => last.len += 1,
+            _ => extents.push(Extent { offset, len: 1 }),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::prelude::*;
+use std::{fs, io, path, str};
+use unicode_normalization::UnicodeNormalization;
+use {metadata_file, stream, Comparison, Error, Options, Res};
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// A kind of difference between lines which is ignored when comparing text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rule {
+    LineEndings,
+    TrailingWhitespace,
+    Bom,
+    Normalization,
+}
+
+impl Rule {
+    /// The kind of difference, as printed.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::LineEndings => "line endings",
+            Rule::TrailingWhitespace => "trailing whitespace",
+            Rule::Bom => "byte order marks",
+            Rule::Normalization => "Unicode normalization",
+        }
+    }
+
+    /// The kind of difference, as printed in JSON.
+    pub fn key(self) -> &'static str {
+        match self {
+            Rule::LineEndings => "line_endings",
+            Rule::TrailingWhitespace => "trailing_whitespace",
+            Rule::Bom => "bom",
+            Rule::Normalization => "normalization",
+        }
+    }
+}
+
+/// A line split into the parts which may be ignored.
+struct Line<'a> {
+    bom: &'a [u8],
+    contents: &'a [u8],
+    trailing: &'a [u8],
+    ending: &'a [u8],
+}
+
+impl<'a> Line<'a> {
+    /// Split `line`, read with its line ending; only the first line of a file
+    /// may start with a byte order mark.
+    fn split(line: &'a [u8], first: bool) -> Self {
+        let (bom, line) = match line.strip_prefix(BOM) {
+            Some(rest) if first => (&line[..BOM.len()], rest),
+            _ => (&line[..0], line),
+        };
+        let len = if line.ends_with(b"\r\n") {
+            line.len() - 2
+        } else if line.ends_with(b"\n") {
+            line.len() - 1
+        } else {
+            line.len()
+        };
+        let (line, ending) = line.split_at(len);
+        let len = line.iter()
+            .rposition(|&byte| !b" \t\x0b\x0c".contains(&byte))
+            .map_or(0, |i| i + 1);
+        let (contents, trailing) = line.split_at(len);
+        Line { bom, contents, trailing, ending }
+    }
+
+    /// The rules under which the line is the same as `other`, or `None` if it
+    /// differs under `rules`.
+    fn compare(&self, other: &Line, rules: &[Rule]) -> Option<Vec<Rule>> {
+        let mut applied = Vec::new();
+        let parts = [
+            (Rule::Bom, self.bom == other.bom),
+            (Rule::LineEndings, self.ending == other.ending),
+            (Rule::TrailingWhitespace, self.trailing == other.trailing),
+            (Rule::Normalization, self.contents == other.contents),
+        ];
+        for &(rule, same) in &parts {
+            if same {
+                continue;
+            }
+            if !rules.contains(&rule) {
+                return None;
+            }
+            if rule == Rule::Normalization &&
+                !same_normalized(self.contents, other.contents)
+            {
+                return None;
+            }
+            applied.push(rule);
+        }
+        Some(applied)
+    }
+}
+
+/// Whether `left` and `right` are UTF-8 text with the same NFC form.
+fn same_normalized(left: &[u8], right: &[u8]) -> bool {
+    match (str::from_utf8(left), str::from_utf8(right)) {
+        (Ok(left), Ok(right)) => left.nfc().eq(right.nfc()),
+        _ => false,
+    }
+}
+
+/// Compare the first of `paths` to each of the others line by line, ignoring
+/// the kinds of differences in `rules`, by reading all of them in a single
+/// sequential pass.
+pub fn compare_text(paths: &[&path::Path], rules: &[Rule], options: &Options) ->
+    Res<Comparison>
+{
+    if paths.iter().filter(|&&path| stream::is_stdin(path)).count() > 1 {
+        return Err(Error::StdinRepeated);
+    }
+    let mut readers = paths.iter()
+        .map(|&path| open(path, options))
+        .collect::<Res<Vec<_>>>()?;
+    let mut lines = vec![Vec::new(); paths.len()];
+    let mut applied = Vec::new();
+
+    let mut number = 0;
+    loop {
+        number += 1;
+        for (i, reader) in readers.iter_mut().enumerate() {
+            lines[i].clear();
+            reader.read_until(b'\n', &mut lines[i])
+                .map_err(|e| (e, paths[i]))?;
+        }
+        if lines.iter().all(|line| line.is_empty()) {
+            break;
+        }
+
+        let (first, rest) = lines.split_first()
+            .expect("no first element in lines");
+        let line_first = Line::split(first, number == 1);
+        for (i, line) in rest.iter().enumerate().map(|(i, l)| (i + 1, l)) {
+            if line == first {
+                continue;
+            }
+            // a file which ended differs from any line, even an empty one,
+            // but for a byte order mark alone in a file otherwise empty
+            let rules_line = if first.is_empty() || line.is_empty() {
+                let ended_or_bom = |line: &[u8]| line.is_empty() ||
+                    number == 1 && line == BOM;
+                if ended_or_bom(first) && ended_or_bom(line) &&
+                    rules.contains(&Rule::Bom)
+                {
+                    Some(vec![Rule::Bom])
+                } else {
+                    None
+                }
+            } else {
+                line_first.compare(&Line::split(line, number == 1), rules)
+            };
+            match rules_line {
+                Some(rules_line) => for rule in rules_line {
+                    if !applied.contains(&rule) {
+                        applied.push(rule);
+                    }
+                },
+                None => return Ok(Comparison::DifferentLine {
+                    left: paths[0].into(),
+                    right: paths[i].into(),
+                    line: number,
+                    text_left: to_text(first),
+                    text_right: to_text(line),
+                }),
+            }
+        }
+    }
+
+    if applied.is_empty() {
+        return Ok(Comparison::Same);
+    }
+    // in the order the rules are listed in
+    applied.sort_by_key(|&rule| {
+        rules.iter().position(|&r| r == rule)
+    });
+    Ok(Comparison::SameText { rules: applied })
+}
+
+fn open(path: &path::Path, options: &Options) -> Res<Box<dyn BufRead>> {
+    if options.verbose {
+        eprintln!("{:?}: reading as text", path);
+    }
+    if stream::is_stdin(path) {
+        let stdin = io::BufReader::with_capacity(options.buffer_size,
+                                                 io::stdin());
+        return Ok(Box::new(stdin));
+    }
+    metadata_file(path, options.symlinks)?;
+    let file = fs::File::open(path).map_err(|e| (e, path))?;
+    Ok(Box::new(io::BufReader::with_capacity(options.buffer_size, file)))
+}
+
+/// The line with its line ending, if any, or `None` at the end of the file.
+fn to_text(line: &[u8]) -> Option<String> {
+    if line.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: &[Rule] = &[Rule::LineEndings, Rule::TrailingWhitespace,
+                           Rule::Bom, Rule::Normalization];
+
+    /// The parts of `line` split as the line at `number`.
+    fn split(line: &[u8], number: usize) -> [&[u8]; 4] {
+        let line = Line::split(line, number == 1);
+        [line.bom, line.contents, line.trailing, line.ending]
+    }
+
+    /// The rules under which the lines `left` and `right`, at `number`, are
+    /// the same.
+    fn compare(left: &str, right: &str, number: usize, rules: &[Rule]) ->
+        Option<Vec<Rule>>
+    {
+        Line::split(left.as_bytes(), number == 1)
+            .compare(&Line::split(right.as_bytes(), number == 1), rules)
+    }
+
+    #[test]
+    fn split_parts() {
+        assert_eq!(split(b"a b\n", 1), [&b""[..], b"a b", b"", b"\n"]);
+        assert_eq!(split(b"a \t\r\n", 2), [&b""[..], b"a", b" \t", b"\r\n"]);
+        assert_eq!(split(b"\xef\xbb\xbfa\x0b\x0c", 1),
+                   [BOM, b"a", b"\x0b\x0c", b""]);
+        // only the first line may start with a byte order mark
+        assert_eq!(split(b"\xef\xbb\xbfa\n", 2),
+                   [&b""[..], b"\xef\xbb\xbfa", b"", b"\n"]);
+        assert_eq!(split(b"\xef\xbb\xbf", 1), [BOM, b"", b"", b""]);
+        assert_eq!(split(b" \r", 1), [&b""[..], b" \r", b"", b""]);
+        assert_eq!(split(b"", 1), [&b""[..], b"", b"", b""]);
+    }
+
+    #[test]
+    fn compare_each_rule() {
+        let cases = [
+            (Rule::LineEndings, "a\n", "a\r\n"),
+            (Rule::TrailingWhitespace, "a\n", "a \t\n"),
+            (Rule::Bom, "a\n", "\u{feff}a\n"),
+            (Rule::Normalization, "\u{e9}\n", "e\u{301}\n"),
+        ];
+        for &(rule, left, right) in &cases {
+            assert_eq!(compare(left, right, 1, &[rule]), Some(vec![rule]));
+            assert_eq!(compare(right, left, 1, ALL), Some(vec![rule]));
+            let others = ALL.iter()
+                .cloned()
+                .filter(|&r| r != rule)
+                .collect::<Vec<_>>();
+            assert_eq!(compare(left, right, 1, &others), None);
+            assert_eq!(compare(left, left, 1, &[]), Some(vec![]));
+        }
+        assert_eq!(compare("a\n", "\u{feff}a\n", 2, ALL), None);
+        assert_eq!(compare("a\n", "b\n", 1, ALL), None);
+        assert!(same_normalized(b"\xc3\xa9", b"e\xcc\x81"));
+        assert!(!same_normalized(b"\xc3\xa9\xff", b"e\xcc\x81\xff"));
+    }
+
+    #[test]
+    fn compare_rules_combined() {
+        assert_eq!(compare("\u{feff}\u{e9} \r\n", "e\u{301}\n", 1, ALL),
+                   Some(vec![Rule::Bom, Rule::LineEndings,
+                             Rule::TrailingWhitespace, Rule::Normalization]));
+        assert_eq!(compare("a \r\n", "a\n", 2,
+                           &[Rule::LineEndings, Rule::TrailingWhitespace]),
+                   Some(vec![Rule::LineEndings, Rule::TrailingWhitespace]));
+        assert_eq!(compare("a \r\n", "a\n", 2, &[Rule::LineEndings]), None);
+    }
+
+    #[test]
+    fn compare_last_line() {
+        assert_eq!(compare("a", "a\n", 2, &[Rule::LineEndings]),
+                   Some(vec![Rule::LineEndings]));
+        assert_eq!(compare("a", "a\n", 2, &[Rule::TrailingWhitespace]), None);
+        assert_eq!(compare("a \t", "a", 2, &[Rule::TrailingWhitespace]),
+                   Some(vec![Rule::TrailingWhitespace]));
+        assert_eq!(compare("a \t", "a\r\n", 2, ALL),
+                   Some(vec![Rule::LineEndings, Rule::TrailingWhitespace]));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::Value;
+use std::{fmt, fs, path};
+use output::{path_to_json, ToJson};
+use {compare_files, compare_symlinks, keep_going, metadata, Comparison, Error,
+     ExitStatus, Options, Res};
+
+pub fn compare_trees(roots: &[&path::Path], options: &Options,
+                     errors: &mut Vec<Error>) -> Res<TreeComparison>
+{
+    let (first, rest) = roots.split_at(1);
+    let first = *first.first().expect("no first element in first");
+    let mut differences = Vec::new();
+    let entries_first =
+        match keep_going(walk(first, options, errors), options, errors)? {
+            Some(entries) => entries,
+            None => return Ok(TreeComparison { differences }),
+        };
+
+    for &root in rest {
+        let entries =
+            match keep_going(walk(root, options, errors), options, errors)? {
+                Some(entries) => entries,
+                None => continue,
+            };
+        let paths = entries_first.types.keys().chain(entries.types.keys())
+            .collect::<BTreeSet<_>>();
+
+        // once a directory is reported as missing or as a different type, its
+        // descendants are not reported separately
+        let mut skip: Option<&path::Path> = None;
+        for path in paths {
+            if let Some(skip) = skip {
+                if path.starts_with(skip) {
+                    continue;
+                }
+            }
+            // the entries of directories which could not be read are not
+            // compared
+            let unreadable = entries_first.unreadable.iter()
+                .chain(&entries.unreadable)
+                .any(|dir| path != dir && path.starts_with(dir));
+            if unreadable {
+                continue;
+            }
+            let left = first.join(path);
+            let right = root.join(path);
+            let types = (entries_first.types.get(path),
+                         entries.types.get(path));
+            let difference = match types {
+                (Some(_), None) => Some(Difference::OnlyIn {
+                    root: first.into(), path: path.clone(),
+                }),
+                (None, Some(_)) => Some(Difference::OnlyIn {
+                    root: root.into(), path: path.clone(),
+                }),
+                (Some(type_left), Some(type_right)) => {
+                    let difference = compare_entries(&left, type_left,
+                                                     &right, type_right,
+                                                     options);
+                    match keep_going(difference, options, errors)? {
+                        Some(difference) => difference,
+                        None => continue,
+                    }
+                },
+                (None, None) => unreachable!("path in neither tree"),
+            };
+            if let Some(difference) = difference {
+                match difference {
+                    Difference::OnlyIn { .. } |
+                    Difference::Different(Comparison::DifferentType { .. }) =>
+                        skip = Some(path),
+                    Difference::Different(..) => (),
+                }
+                differences.push(difference);
+            }
+        }
+    }
+    Ok(TreeComparison { differences })
+}
+
+fn compare_entries(left: &path::Path, type_left: &fs::FileType,
+                   right: &path::Path, type_right: &fs::FileType,
+                   options: &Options) -> Res<Option<Difference>>
+{
+    let comparison = if type_left != type_right {
+        Comparison::DifferentType {
+            left: left.into(), type_left: *type_left,
+            right: right.into(), type_right: *type_right,
+        }
+    } else if type_left.is_file() {
+        let len_left = fs::metadata(left).map_err(|e| (e, left))?.len();
+        let len_right = fs::metadata(right).map_err(|e| (e, right))?.len();
+        compare_files(left, len_left, right, len_right, options)?
+    } else if type_left.is_symlink() {
+        compare_symlinks(left, right)?
+    } else {
+        // directories are compared through their entries; other file types
+        // have no contents to compare
+        Comparison::Same
+    };
+    let comparison = match comparison {
+        Comparison::Same | Comparison::SameFile { .. } =>
+            match metadata::compare(left, right, &options.attributes, false)? {
+                Some(comparison) => comparison,
+                None => return Ok(None),
+            },
+        comparison => comparison,
+    };
+    Ok(Some(Difference::Different(comparison)))
+}
+
+/// The entries under a directory, with paths relative to it.
+pub struct Entries {
+    /// The file type of each entry, without following symbolic links.
+    pub types: BTreeMap<path::PathBuf, fs::FileType>,
+    /// The directories whose entries could not be read, with --keep-going.
+    pub unreadable: BTreeSet<path::PathBuf>,
+}
+
+pub fn walk(root: &path::Path, options: &Options, errors: &mut Vec<Error>) ->
+    Res<Entries>
+{
+    let metadata = fs::metadata(root).map_err(|e| (e, root))?;
+    if !metadata.is_dir() {
+        return Err(Error::FileTypeUnsupported(
+            root.into(), metadata.file_type()));
+    }
+
+    let mut entries = Entries {
+        types: BTreeMap::new(),
+        unreadable: BTreeSet::new(),
+    };
+    let mut dirs = vec![path::PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let dir_full = root.join(&dir);
+        let read_dir = fs::read_dir(&dir_full)
+            .map_err(|e| Error::from((e, &dir_full)));
+        let read_dir = match keep_going(read_dir, options, errors)? {
+            Some(read_dir) => read_dir,
+            None => {
+                entries.unreadable.insert(dir);
+                continue;
+            },
+        };
+        for entry in read_dir {
+            let entry = entry.map_err(|e| (e, &dir_full))?;
+            let file_type = entry.file_type().map_err(|e| (e, entry.path()))?;
+            let path = dir.join(entry.file_name());
+            if file_type.is_dir() {
+                dirs.push(path.clone());
+            }
+            entries.types.insert(path, file_type);
+        }
+    }
+    Ok(entries)
+}
+
+pub struct TreeComparison {
+    differences: Vec<Difference>,
+}
+
+impl fmt::Display for TreeComparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.differences.is_empty() {
+            return write!(f, "directory trees are the same");
+        }
+        for (i, difference) in self.differences.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToJson for TreeComparison {
+    fn to_json(&self) -> Vec<Value> {
+        if self.differences.is_empty() {
+            return vec![json!({ "kind": "same" })];
+        }
+        self.differences.iter()
+            .flat_map(|difference| match difference {
+                Difference::OnlyIn { root, path } => vec![json!({
+                    "kind": "only_in",
+                    "root": path_to_json(root),
+                    "path": path_to_json(path),
+                })],
+                Difference::Different(comparison) => comparison.to_json(),
+            })
+            .collect()
+    }
+}
+
+impl From<TreeComparison> for ExitStatus {
+    fn from(comparison: TreeComparison) -> Self {
+        if comparison.differences.is_empty() {
+            ExitStatus::Same
+        } else {
+            ExitStatus::Different
+        }
+    }
+}
+
+enum Difference {
+    OnlyIn { root: path::PathBuf, path: path::PathBuf },
+    Different(Comparison),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::OnlyIn { root, path } =>
+                write!(f, "only in {:?}: {:?}", root, path),
+            Difference::Different(comparison) => comparison.fmt(f),
+        }
+    }
+}
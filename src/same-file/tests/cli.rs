@@ -0,0 +1,125 @@
+use std::{env, fs, path, process};
+
+/// A directory of its own for a test, emptied first.
+fn dir(name: &str) -> path::PathBuf {
+    let dir = env::temp_dir()
+        .join(format!("same-file-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run the binary in `dir` with `args`, followed by the option not to use the
+/// cache, and return its exit status and standard output.
+fn run(dir: &path::Path, args: &[&str]) -> (i32, String) {
+    let output = process::Command::new(env!("CARGO_BIN_EXE_same-file"))
+        .args(args)
+        .arg("--no-cache")
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    (output.status.code().unwrap(),
+     String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn manifest_create_verify() {
+    let dir = dir("manifest");
+    fs::create_dir(dir.join("files")).unwrap();
+    fs::write(dir.join("files/a"), "a\n").unwrap();
+    fs::write(dir.join("files/b"), "b\n").unwrap();
+
+    let (status, manifest) = run(&dir, &["manifest", "create", "files"]);
+    assert_eq!(status, 0);
+    assert_eq!(manifest.lines().count(), 2);
+    fs::write(dir.join("manifest"), manifest).unwrap();
+    assert_eq!(run(&dir, &["manifest", "verify", "manifest", "files"]).0, 0);
+
+    fs::write(dir.join("files/b"), "c\n").unwrap();
+    assert_eq!(run(&dir, &["manifest", "verify", "manifest", "files"]).0, 1);
+}
+
+#[test]
+fn manifest_verify_dot_paths() {
+    let dir = dir("manifest-dot");
+    fs::create_dir_all(dir.join("files/sub")).unwrap();
+    fs::write(dir.join("files/a"), "a\n").unwrap();
+    fs::write(dir.join("files/sub/b"), "b\n").unwrap();
+    // as written by `find . -type f -exec sha256sum {} +` in the directory
+    fs::write(dir.join("manifest"), concat!(
+        "87428fc522803d31065e7bce3cf03fe475096631e5e07bbd7a0fde60c4cf25c7",
+        "  ./a\n",
+        "0263829989b6fd954f72baaf2fc64bc2e2f01d692d4de72986ea808f6e99813f",
+        "  ./sub/b\n")).unwrap();
+    assert_eq!(run(&dir, &["manifest", "verify", "manifest", "files"]),
+               (0, "files match the manifest\n".to_string()));
+}
+
+#[test]
+fn paths_named_as_subcommands() {
+    let dir = dir("subcommands");
+    for name in &["help", "hl", "manifest", "manifests", "other"] {
+        fs::write(dir.join(name), "a\n").unwrap();
+    }
+    for name in &["help", "hl", "manifest", "manifests"] {
+        assert_eq!(run(&dir, &["other", name]).0, 0);
+    }
+    for name in &["help", "hl", "manifests"] {
+        assert_eq!(run(&dir, &[name, "other"]).0, 0);
+    }
+    assert_eq!(run(&dir, &["./manifest", "other"]).0, 0);
+}
+
+#[test]
+fn same_file_reported() {
+    let dir = dir("same-file");
+    fs::write(dir.join("a"), "a\n").unwrap();
+    fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+    fs::write(dir.join("c"), "a\n").unwrap();
+
+    let (status, output) = run(&dir, &["a", "b"]);
+    assert_eq!(status, 0);
+    assert!(output.starts_with("files are the same: the same file"));
+    let (status, output) = run(&dir, &["--format=json", "a", "b"]);
+    assert_eq!(status, 0);
+    assert!(output.contains("\"reason\":\"same_inode\""));
+    assert_eq!(run(&dir, &["a", "b", "c"]),
+               (0, "files are the same\n".to_string()));
+}
+
+#[test]
+fn json_summary_results() {
+    let dir = dir("json-summary");
+    fs::create_dir_all(dir.join("tree/sub")).unwrap();
+    fs::write(dir.join("tree/a"), "a\n").unwrap();
+    fs::write(dir.join("tree/sub/b"), "b\n").unwrap();
+
+    assert_eq!(run(&dir, &["-r", "--format=ndjson", "tree", "tree"]),
+               (0, "{\"kind\":\"same\",\"mode\":\"recursive\",\
+                    \"version\":1}\n".to_string()));
+    assert_eq!(run(&dir, &["-d", "--format=ndjson", "tree"]),
+               (1, "{\"kind\":\"no_duplicates\",\"mode\":\"find-duplicates\",\
+                    \"version\":1}\n".to_string()));
+    let (_, manifest) = run(&dir, &["manifest", "create", "tree"]);
+    fs::write(dir.join("manifest"), manifest).unwrap();
+    assert_eq!(run(&dir, &["manifest", "verify", "--format=json", "manifest",
+                           "tree"]),
+               (0, "{\"mode\":\"manifest-verify\",\
+                    \"results\":[{\"kind\":\"same\"}],\"version\":1}\n"
+                .to_string()));
+}
+
+#[test]
+fn text_bom_only() {
+    let dir = dir("text-bom");
+    fs::write(dir.join("bom"), "\u{feff}").unwrap();
+    fs::write(dir.join("empty"), "").unwrap();
+    fs::write(dir.join("newline"), "\n").unwrap();
+
+    for args in &[["bom", "empty"], ["empty", "bom"]] {
+        let args = [&["--text", "--ignore-bom"], &args[..]].concat();
+        assert_eq!(run(&dir, &args).0, 0);
+    }
+    assert_eq!(run(&dir, &["--text", "bom", "empty"]).0, 1);
+    assert_eq!(run(&dir, &["--text", "--ignore-bom", "bom", "newline"]).0, 1);
+}